@@ -0,0 +1,494 @@
+use anyhow::{Context, Result};
+use log::warn;
+use prometheus::{IntGauge, IntGaugeVec};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{CircuitBreaker, Http},
+    utils::{host_of, jitter},
+};
+
+/// A pool of Clients keyed by the upstream host and the HTTP policy.
+///
+/// Targets requesting the same host with the same policy share one Client,
+/// so they reuse the kept-alive connections and TLS sessions of its Agent.
+/// Targets requesting the same host share one Breaker no matter the policy.
+pub(crate) struct Clients {
+    clients: Mutex<HashMap<(String, Http), Arc<Client>>>,
+    breakers: Mutex<HashMap<String, Arc<Breaker>>>,
+    breaker_cfg: CircuitBreaker,
+    breaker_states: IntGaugeVec,
+}
+
+impl Clients {
+    /// Returns a Clients instance,
+    /// the state of every host's Breaker is exported into the breaker_states gauge.
+    pub(crate) fn new(breaker_cfg: &CircuitBreaker, breaker_states: IntGaugeVec) -> Self {
+        Clients {
+            clients: Mutex::new(HashMap::new()),
+            breakers: Mutex::new(HashMap::new()),
+            breaker_cfg: breaker_cfg.clone(),
+            breaker_states,
+        }
+    }
+
+    /// Returns the shared Client of the addr's host and the policy,
+    /// creates one if there is none yet.
+    pub(crate) fn get(&self, addr: &str, cfg: &Http) -> Arc<Client> {
+        let host = host_of(addr);
+        let breaker = self
+            .breakers
+            .lock()
+            .expect("lock breakers failed")
+            .entry(host.to_string())
+            .or_insert_with(|| {
+                Arc::new(Breaker::new(
+                    host,
+                    &self.breaker_cfg,
+                    Some(self.breaker_states.with_label_values(&[host])),
+                ))
+            })
+            .clone();
+
+        self.clients
+            .lock()
+            .expect("lock clients failed")
+            .entry((host.to_string(), cfg.clone()))
+            .or_insert_with(|| Arc::new(Client::new(cfg, breaker)))
+            .clone()
+    }
+
+    /// Drops the Clients and Breakers of the hosts none of the addrs requests,
+    /// the states of the dropped Breakers are removed from the breaker_states gauge.
+    pub(crate) fn retain<'a, I>(&self, addrs: I)
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let hosts: HashSet<&str> = addrs.into_iter().map(host_of).collect();
+        self.clients
+            .lock()
+            .expect("lock clients failed")
+            .retain(|(host, _), _| hosts.contains(host.as_str()));
+        self.breakers
+            .lock()
+            .expect("lock breakers failed")
+            .retain(|host, _| {
+                let keep = hosts.contains(host.as_str());
+                if !keep {
+                    let _ = self.breaker_states.remove_label_values(&[host]);
+                }
+                keep
+            });
+    }
+}
+
+/// A HTTP client wrapping an ureq Agent with timeouts, a retry policy and a circuit breaker.
+///
+/// Every task should request its upstream through a Client
+/// for sharing the same timeouts and retry behavior.
+pub(crate) struct Client {
+    agent: ureq::Agent,
+    breaker: Arc<Breaker>,
+    max_retries: u32,
+    backoff_initial: Duration,
+    backoff_max: Duration,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::new(
+            &Http::default(),
+            Arc::new(Breaker::new("", &CircuitBreaker::default(), None)),
+        )
+    }
+}
+
+impl Client {
+    /// Returns a Client instance by the http config.
+    pub(crate) fn new(cfg: &Http, breaker: Arc<Breaker>) -> Self {
+        Client {
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_millis(cfg.connect_timeout_ms))
+                .timeout_read(Duration::from_millis(cfg.read_timeout_ms))
+                .timeout(Duration::from_millis(cfg.timeout_ms))
+                .max_idle_connections_per_host(cfg.max_idle_connections_per_host)
+                .build(),
+            breaker,
+            max_retries: cfg.max_retries,
+            backoff_initial: Duration::from_millis(cfg.backoff_initial_ms),
+            backoff_max: Duration::from_millis(cfg.backoff_max_ms),
+        }
+    }
+
+    /// Sends a GET request and decodes the response body as JSON.
+    pub(crate) fn get_json(&self, url: &str) -> Result<Value> {
+        self.retry(self.agent.get(url), None)
+    }
+
+    /// Sends a POST request with a JSON body and decodes the response body as JSON.
+    pub(crate) fn post_json(&self, url: &str, body: &Value) -> Result<Value> {
+        self.retry(self.agent.post(url), Some(body))
+    }
+
+    /// Sends the request until it succeeds, fails with a non-retryable error
+    /// or runs out of retries, sleeping with a jittered exponential backoff between attempts.
+    ///
+    /// Fails fast without sending anything while the host's circuit breaker is open.
+    fn retry(&self, request: ureq::Request, body: Option<&Value>) -> Result<Value> {
+        if !self.breaker.allow() {
+            return Err(CircuitOpen(self.breaker.host.clone()).into());
+        }
+
+        let request = request.set("Accept", "application/json");
+        let mut attempt = 0;
+        loop {
+            let result = match body {
+                Some(body) => request.clone().send_json(body),
+                None => request.clone().call(),
+            };
+
+            match result {
+                Ok(response) => {
+                    self.breaker.on_success();
+                    return response.into_json().context("ureq json failed");
+                }
+                Err(e) if attempt < self.max_retries && is_retryable(&e) => {
+                    thread::sleep(self.backoff(attempt));
+                    attempt += 1;
+                }
+                Err(e) => {
+                    // only an unreachable or erroring host trips the breaker,
+                    // a client error means the request itself is wrong
+                    if is_retryable(&e) {
+                        self.breaker.on_failure();
+                    } else {
+                        self.breaker.on_success();
+                    }
+                    return Err(e)
+                        .with_context(|| format!("ureq call failed, attempts:{}", attempt + 1));
+                }
+            }
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        backoff(self.backoff_initial, self.backoff_max, attempt)
+    }
+}
+
+/// Returns the backoff of the attempt which is half of the exponential delay
+/// plus a random amount up to the other half.
+pub(crate) fn backoff(initial: Duration, max: Duration, attempt: u32) -> Duration {
+    let delay = initial
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(max);
+    let half = delay.as_millis() as u64 / 2;
+    Duration::from_millis(half + jitter(half))
+}
+
+/// The error of requesting a host whose circuit breaker is open.
+#[derive(Debug)]
+pub(crate) struct CircuitOpen(String);
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "circuit breaker is open, host:{:?}", self.0)
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// The state of a Breaker which is exported as the value of its gauge.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed = 0,
+    Open = 1,
+    HalfOpen = 2,
+}
+
+/// A circuit breaker of a host.
+///
+/// It opens after failure_threshold consecutive failures and rejects requests for open_ms,
+/// then lets a single probe request through in the half-open state,
+/// closes again if the probe succeeds otherwise reopens.
+pub(crate) struct Breaker {
+    host: String,
+    failure_threshold: u32,
+    open: Duration,
+    inner: Mutex<BreakerInner>,
+    gauge: Option<IntGauge>,
+}
+
+struct BreakerInner {
+    state: State,
+    failures: u32,
+    opened_at: Instant,
+}
+
+impl Breaker {
+    pub(crate) fn new(host: &str, cfg: &CircuitBreaker, gauge: Option<IntGauge>) -> Self {
+        if let Some(g) = &gauge {
+            g.set(State::Closed as i64);
+        }
+        Breaker {
+            host: host.to_string(),
+            failure_threshold: cfg.failure_threshold,
+            open: Duration::from_millis(cfg.open_ms),
+            inner: Mutex::new(BreakerInner {
+                state: State::Closed,
+                failures: 0,
+                opened_at: Instant::now(),
+            }),
+            gauge,
+        }
+    }
+
+    /// Returns true if a request is allowed to be sent.
+    fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().expect("lock breaker failed");
+        match inner.state {
+            State::Closed => true,
+            State::Open if inner.opened_at.elapsed() >= self.open => {
+                self.transit(&mut inner, State::HalfOpen);
+                true
+            }
+            State::Open | State::HalfOpen => false,
+        }
+    }
+
+    fn on_success(&self) {
+        let mut inner = self.inner.lock().expect("lock breaker failed");
+        inner.failures = 0;
+        if inner.state != State::Closed {
+            self.transit(&mut inner, State::Closed);
+        }
+    }
+
+    fn on_failure(&self) {
+        let mut inner = self.inner.lock().expect("lock breaker failed");
+        inner.failures = inner.failures.saturating_add(1);
+        let trip = match inner.state {
+            State::HalfOpen => true,
+            // a zero threshold disables the breaker
+            _ => self.failure_threshold > 0 && inner.failures >= self.failure_threshold,
+        };
+        if trip {
+            inner.opened_at = Instant::now();
+            if inner.state != State::Open {
+                self.transit(&mut inner, State::Open);
+            }
+        }
+    }
+
+    fn transit(&self, inner: &mut BreakerInner, state: State) {
+        warn!(
+            "circuit breaker of host:{:?} goes from {:?} to {:?}, consecutive failures:{}",
+            self.host, inner.state, state, inner.failures
+        );
+        inner.state = state;
+        if let Some(g) = &self.gauge {
+            g.set(state as i64);
+        }
+    }
+}
+
+/// Transport errors, rate limiting and server side errors are worth another try,
+/// the other status codes will not change by retrying.
+pub(crate) fn is_retryable(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::Status(code, _) => *code == 429 || *code >= 500,
+        ureq::Error::Transport(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::serve;
+    use prometheus::core::Collector;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Serves the status codes in order and then 200 with an empty JSON object,
+    /// returns the served address and the count of received requests.
+    fn serve_codes(codes: Vec<u16>) -> (String, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let c = count.clone();
+        let addr = serve(move |_| {
+            let n = c.fetch_add(1, Ordering::SeqCst);
+            match codes.get(n) {
+                Some(code) => tiny_http::Response::from_string("")
+                    .with_status_code(*code)
+                    .boxed(),
+                None => tiny_http::Response::from_string("{}").boxed(),
+            }
+        });
+        (addr, count)
+    }
+
+    fn client(max_retries: u32) -> Client {
+        Client::new(
+            &Http {
+                max_retries,
+                backoff_initial_ms: 1,
+                backoff_max_ms: 10,
+                ..Default::default()
+            },
+            Arc::new(Breaker::new("", &CircuitBreaker::default(), None)),
+        )
+    }
+
+    fn clients() -> Clients {
+        let states =
+            IntGaugeVec::new(prometheus::Opts::new("states", "states"), &["host"]).unwrap();
+        Clients::new(&CircuitBreaker::default(), states)
+    }
+
+    #[test]
+    fn test_clients_share_by_host_and_policy() {
+        let clients = clients();
+        let http = Http::default();
+        let a = clients.get("https://rpc.findora.org:8545", &http);
+        let b = clients.get("https://rpc.findora.org:8545/some/path", &http);
+        assert!(Arc::ptr_eq(&a, &b));
+
+        let c = clients.get("https://archive.findora.org:8545", &http);
+        assert!(!Arc::ptr_eq(&a, &c));
+
+        let d = clients.get(
+            "https://rpc.findora.org:8545",
+            &Http {
+                max_retries: 5,
+                ..Default::default()
+            },
+        );
+        assert!(!Arc::ptr_eq(&a, &d));
+        assert!(Arc::ptr_eq(&a.breaker, &d.breaker));
+    }
+
+    #[test]
+    fn test_clients_retain() {
+        let states =
+            IntGaugeVec::new(prometheus::Opts::new("states", "states"), &["host"]).unwrap();
+        let clients = Clients::new(&CircuitBreaker::default(), states.clone());
+        let http = Http::default();
+        let a = clients.get("https://rpc.findora.org:8545", &http);
+        let b = clients.get("https://archive.findora.org:8545", &http);
+        assert_eq!(2, states.collect()[0].get_metric().len());
+
+        clients.retain(["https://rpc.findora.org:8545/some/path"]);
+        assert!(Arc::ptr_eq(
+            &a,
+            &clients.get("https://rpc.findora.org:8545", &http)
+        ));
+        assert_eq!(1, states.collect()[0].get_metric().len());
+        assert!(!Arc::ptr_eq(
+            &b,
+            &clients.get("https://archive.findora.org:8545", &http)
+        ));
+    }
+
+    #[test]
+    fn test_breaker() {
+        let states =
+            IntGaugeVec::new(prometheus::Opts::new("states", "states"), &["host"]).unwrap();
+        let cfg = CircuitBreaker {
+            failure_threshold: 2,
+            open_ms: 50,
+        };
+        let b = Breaker::new("h", &cfg, Some(states.with_label_values(&["h"])));
+        let state = || states.with_label_values(&["h"]).get();
+
+        assert!(b.allow());
+        b.on_failure();
+        assert!(b.allow());
+        assert_eq!(State::Closed as i64, state());
+        b.on_failure();
+        assert!(!b.allow());
+        assert_eq!(State::Open as i64, state());
+
+        thread::sleep(Duration::from_millis(60));
+        // only one probe is allowed in the half-open state
+        assert!(b.allow());
+        assert!(!b.allow());
+        assert_eq!(State::HalfOpen as i64, state());
+
+        // a failed probe reopens the breaker
+        b.on_failure();
+        assert!(!b.allow());
+        assert_eq!(State::Open as i64, state());
+
+        thread::sleep(Duration::from_millis(60));
+        assert!(b.allow());
+        b.on_success();
+        assert!(b.allow());
+        assert_eq!(State::Closed as i64, state());
+    }
+
+    #[test]
+    fn test_client_fails_fast_when_breaker_is_open() {
+        let (addr, count) = serve_codes(vec![500, 500, 500]);
+        let breaker = Arc::new(Breaker::new(
+            &addr,
+            &CircuitBreaker {
+                failure_threshold: 1,
+                open_ms: 60000,
+            },
+            None,
+        ));
+        let c = Client::new(
+            &Http {
+                max_retries: 0,
+                ..Default::default()
+            },
+            breaker,
+        );
+        assert!(c.get_json(&addr).is_err());
+        let e = c.get_json(&addr).unwrap_err();
+        assert!(e.downcast_ref::<CircuitOpen>().is_some());
+        assert_eq!(1, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_client_retries_server_errors() {
+        let (addr, count) = serve_codes(vec![500, 503]);
+        assert!(client(2).get_json(&addr).is_ok());
+        assert_eq!(3, count.load(Ordering::SeqCst));
+
+        let (addr, count) = serve_codes(vec![500, 503]);
+        assert!(client(1).post_json(&addr, &Value::Null).is_err());
+        assert_eq!(2, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_client_does_not_retry_client_errors() {
+        let (addr, count) = serve_codes(vec![404]);
+        assert!(client(3).get_json(&addr).is_err());
+        assert_eq!(1, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_client_backoff() {
+        let c = Client::new(
+            &Http {
+                backoff_initial_ms: 100,
+                backoff_max_ms: 1000,
+                ..Default::default()
+            },
+            Arc::new(Breaker::new("", &CircuitBreaker::default(), None)),
+        );
+        for (attempt, (min, max)) in [(50, 100), (100, 200), (200, 400), (400, 800), (500, 1000)]
+            .iter()
+            .enumerate()
+        {
+            let b = c.backoff(attempt as u32).as_millis() as u64;
+            assert!(*min <= b && b <= *max, "attempt:{} backoff:{}", attempt, b);
+        }
+    }
+}
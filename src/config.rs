@@ -1,6 +1,9 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine};
 use serde::{Deserialize, Serialize};
 
+use crate::utils::calculate_hash;
+
 use std::{
     collections::HashMap,
     fs::File,
@@ -14,6 +17,8 @@ pub(crate) const DEFAULT_CONFIG_PATH: &str = "config.json";
 /// structure if the input file path does not exist.
 ///
 /// The default config path equals the const DEFAULT_CONFIG_PATH variable.
+///
+/// Returns error if a target would be scheduled with a zero interval.
 pub(crate) fn read_config(path: &Path) -> Result<Config> {
     let mut cfg = Config::default();
     if path.exists() {
@@ -22,6 +27,17 @@ pub(crate) fn read_config(path: &Path) -> Result<Config> {
             .with_context(|| format!("deserialize config file failed: {:?}", path))?;
     }
 
+    if cfg.crawler.frequency_ms == 0 {
+        bail!("the frequency_ms of crawler is zero");
+    }
+    if let Some(t) = cfg
+        .crawler
+        .targets
+        .iter()
+        .find(|t| t.frequency_ms == Some(0))
+    {
+        bail!("the frequency_ms of target:{} is zero", t.host_addr);
+    }
     Ok(cfg)
 }
 
@@ -31,6 +47,12 @@ pub(crate) struct Config {
     pub(crate) log_level: String,
     pub(crate) crawler: Crawler,
     pub(crate) server: Server,
+    /// Pushes the metrics to a Prometheus Pushgateway if set.
+    pub(crate) pushgateway: Option<Pushgateway>,
+    /// Sends the samples to a Prometheus remote_write endpoint if set.
+    pub(crate) remote_write: Option<RemoteWrite>,
+    /// The output sinks every crawl result is emitted to.
+    pub(crate) sinks: Vec<Sink>,
 }
 
 impl Default for Config {
@@ -39,6 +61,9 @@ impl Default for Config {
             log_level: "trace".to_string(),
             crawler: Crawler::default(),
             server: Server::default(),
+            pushgateway: None,
+            remote_write: None,
+            sinks: vec![],
         }
     }
 }
@@ -48,7 +73,23 @@ impl Default for Config {
 pub(crate) struct Crawler {
     pub(crate) targets: Vec<Target>,
     pub(crate) worker_n: usize,
+    /// The default scrape interval of targets which do not specify their own.
     pub(crate) frequency_ms: u64,
+    /// The capacity of the queue between the task pusher and the workers.
+    pub(crate) queue_size: usize,
+    /// How long closing the crawler waits for in-flight tasks before giving up on them.
+    pub(crate) shutdown_grace_ms: u64,
+    /// The default HTTP policy of targets which do not specify their own.
+    pub(crate) http: Http,
+    pub(crate) circuit_breaker: CircuitBreaker,
+    pub(crate) mode: CrawlMode,
+    /// How long the values of an on-demand scrape are reused by the following scrapes.
+    pub(crate) cache_ttl_ms: u64,
+    /// Publishes the values scaled into integers like the versions before real units did,
+    /// get_price and the balances are multiplied by 1e6 and consensus_power by 100.
+    pub(crate) legacy_scaling: bool,
+    /// The labels attached to the series of targets automatically.
+    pub(crate) auto_labels: AutoLabels,
 }
 
 impl Default for Crawler {
@@ -56,19 +97,48 @@ impl Default for Crawler {
         Crawler {
             worker_n: 3,
             frequency_ms: 15000,
+            queue_size: 128,
+            shutdown_grace_ms: 5000,
+            http: Http::default(),
+            circuit_breaker: CircuitBreaker::default(),
+            mode: CrawlMode::Interval,
+            cache_ttl_ms: 5000,
+            legacy_scaling: false,
+            auto_labels: AutoLabels::default(),
             targets: vec![Target {
                 host_addr: "http://127.0.0.1:26657".to_string(),
                 task_name: TaskName::NetworkFunctional,
                 registry: None,
                 extra_opts: None,
+                frequency_ms: None,
+                http: None,
+                fallback_addrs: vec![],
+                endpoint_mode: EndpointMode::Priority,
+                quorum: 0,
+                max_age_ms: None,
+                stale_policy: StalePolicy::Drop,
+                metric_name: None,
+                help: None,
+                unit: None,
             }],
         }
     }
 }
 
-#[derive(Debug, PartialEq, Hash, Serialize, Deserialize)]
+/// The way of triggering targets.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum CrawlMode {
+    /// Runs every target on its own interval.
+    #[default]
+    Interval,
+    /// Runs every target when Prometheus scrapes the /metrics path.
+    OnDemand,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Hash, Serialize, Deserialize)]
 pub(crate) enum TaskName {
     ConsensusPower,
+    #[default]
     NetworkFunctional,
     TotalCountOfValidators,
     TotalBalanceOfRelayers,
@@ -78,9 +148,19 @@ pub(crate) enum TaskName {
     GetPrice,
 }
 
-impl Default for TaskName {
-    fn default() -> Self {
-        TaskName::NetworkFunctional
+impl TaskName {
+    /// Returns the snake case name of the task.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            TaskName::ConsensusPower => "consensus_power",
+            TaskName::NetworkFunctional => "network_functional",
+            TaskName::TotalCountOfValidators => "total_count_of_validators",
+            TaskName::TotalBalanceOfRelayers => "total_balance_of_relayers",
+            TaskName::BridgedBalance => "bridged_balance",
+            TaskName::BridgedSupply => "bridged_supply",
+            TaskName::NativeBalance => "native_balance",
+            TaskName::GetPrice => "get_price",
+        }
     }
 }
 
@@ -110,6 +190,39 @@ pub(crate) enum ExtraOpts {
     },
 }
 
+impl ExtraOpts {
+    /// Returns what the target is about, the values of its identifying labels joined by '/',
+    /// which are addresses or a currency pair telling targets of the same task apart.
+    pub(crate) fn subject(&self) -> String {
+        let values: Vec<&str> = self.labels().into_iter().map(|(_, v)| v).collect();
+        values.join("/")
+    }
+
+    /// Returns the label names and values identifying the target.
+    pub(crate) fn labels(&self) -> Vec<(&'static str, &str)> {
+        match self {
+            ExtraOpts::TotalBalanceOfRelayers { bridge_address, .. } => {
+                vec![("bridge_address", bridge_address)]
+            }
+            ExtraOpts::BridgedBalance {
+                erc20handler_address,
+                token_address,
+                ..
+            } => vec![
+                ("token_address", token_address),
+                ("holder_address", erc20handler_address),
+            ],
+            ExtraOpts::BridgedSupply { token_address, .. } => {
+                vec![("token_address", token_address)]
+            }
+            ExtraOpts::NativeBalance { native_address, .. } => {
+                vec![("holder_address", native_address)]
+            }
+            ExtraOpts::GetPrice { currency_pair, .. } => vec![("currency_pair", currency_pair)],
+        }
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub(crate) struct Target {
@@ -117,6 +230,66 @@ pub(crate) struct Target {
     pub(crate) task_name: TaskName,
     pub(crate) registry: Option<Registry>,
     pub(crate) extra_opts: Option<ExtraOpts>,
+    /// The scrape interval of this target,
+    /// falls back to the crawler's frequency_ms if None.
+    pub(crate) frequency_ms: Option<u64>,
+    /// The HTTP policy of this target,
+    /// falls back to the crawler's http if None.
+    pub(crate) http: Option<Http>,
+    /// Other endpoints serving the same data as host_addr.
+    pub(crate) fallback_addrs: Vec<String>,
+    /// How host_addr and fallback_addrs are picked.
+    pub(crate) endpoint_mode: EndpointMode,
+    /// The number of endpoints which have to agree on a value in the Quorum mode,
+    /// zero means the majority of endpoints.
+    pub(crate) quorum: usize,
+    /// How long the latest successful value stays fresh,
+    /// the value never goes stale if None.
+    pub(crate) max_age_ms: Option<u64>,
+    /// How a stale value is exposed.
+    pub(crate) stale_policy: StalePolicy,
+    /// The name of the task's metric replacing the default one,
+    /// the prefix of registry still applies.
+    pub(crate) metric_name: Option<String>,
+    /// The help text of the task's metric replacing the default one.
+    pub(crate) help: Option<String>,
+    /// The unit of the task's metric, which is appended to the metric name
+    /// unless the name already ends with it.
+    pub(crate) unit: Option<String>,
+}
+
+/// The way of picking endpoints of a target.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum EndpointMode {
+    /// Always starts from host_addr and falls back to fallback_addrs in order.
+    #[default]
+    Priority,
+    /// Starts from the next endpoint of the previous run and falls back to the rest in order.
+    RoundRobin,
+    /// Asks all endpoints and publishes the value agreed by a quorum of them.
+    Quorum,
+}
+
+/// The way of exposing a value older than the max age of its target.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum StalePolicy {
+    /// Leaves the series out of the exposition.
+    #[default]
+    Drop,
+    /// Exposes the series with a NaN value.
+    NaN,
+    /// Exposes the series with the stale label set to true, fresh series have it set to false.
+    Label,
+}
+
+impl Target {
+    /// Returns a hash of the whole configuration of the target,
+    /// unlike calculate_hash of the target which only identifies it.
+    pub(crate) fn fingerprint(&self) -> u64 {
+        // the maps of a json value are sorted so the labels of registry hash stably
+        let value = serde_json::to_value(self).unwrap_or_default();
+        calculate_hash(&value.to_string())
+    }
 }
 
 impl Hash for Target {
@@ -133,6 +306,105 @@ impl PartialEq for Target {
     }
 }
 
+/// The timeouts and the retry policy of requesting a target.
+///
+/// A failed request is retried after a jittered exponential backoff
+/// starting from backoff_initial_ms and capped at backoff_max_ms.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Http {
+    pub(crate) connect_timeout_ms: u64,
+    pub(crate) read_timeout_ms: u64,
+    /// The overall timeout of a single request attempt.
+    pub(crate) timeout_ms: u64,
+    pub(crate) max_retries: u32,
+    pub(crate) backoff_initial_ms: u64,
+    pub(crate) backoff_max_ms: u64,
+    /// The number of kept-alive connections to a single host.
+    pub(crate) max_idle_connections_per_host: usize,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Http {
+            connect_timeout_ms: 3000,
+            read_timeout_ms: 5000,
+            timeout_ms: 10000,
+            max_retries: 2,
+            backoff_initial_ms: 200,
+            backoff_max_ms: 2000,
+            max_idle_connections_per_host: 8,
+        }
+    }
+}
+
+/// The circuit breaker policy of every upstream host.
+///
+/// A host is quarantined for open_ms after failure_threshold consecutive failed requests,
+/// a zero failure_threshold disables the circuit breaker.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct CircuitBreaker {
+    pub(crate) failure_threshold: u32,
+    pub(crate) open_ms: u64,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker {
+            failure_threshold: 5,
+            open_ms: 60000,
+        }
+    }
+}
+
+/// The labels attached to the series of targets automatically,
+/// the labels of a target's registry take precedence over them.
+#[derive(Debug, Clone, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct AutoLabels {
+    /// The scheme and authority of host_addr.
+    pub(crate) host: bool,
+    /// The snake case name of the task.
+    pub(crate) task: bool,
+    /// The token_address of BridgedBalance and BridgedSupply.
+    pub(crate) token_address: bool,
+    /// The erc20handler_address of BridgedBalance and the native_address of NativeBalance.
+    pub(crate) holder_address: bool,
+    /// The bridge_address of TotalBalanceOfRelayers.
+    pub(crate) bridge_address: bool,
+    /// The currency_pair of GetPrice.
+    pub(crate) currency_pair: bool,
+}
+
+impl Default for AutoLabels {
+    fn default() -> Self {
+        AutoLabels {
+            host: true,
+            task: true,
+            token_address: true,
+            holder_address: true,
+            bridge_address: true,
+            currency_pair: true,
+        }
+    }
+}
+
+impl AutoLabels {
+    /// Returns whether the label is attached automatically.
+    pub(crate) fn is_enabled(&self, label: &str) -> bool {
+        match label {
+            "host" => self.host,
+            "task" => self.task,
+            "token_address" => self.token_address,
+            "holder_address" => self.holder_address,
+            "bridge_address" => self.bridge_address,
+            "currency_pair" => self.currency_pair,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 #[serde(default)]
 pub(crate) struct Registry {
@@ -144,17 +416,158 @@ pub(crate) struct Registry {
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(default)]
 pub(crate) struct Server {
+    /// Serves the /metrics path, the exporter only pushes its metrics if false.
+    pub(crate) enabled: bool,
     pub(crate) listen_addr: String,
+    /// How long an on-demand scrape waits for targets
+    /// if the scraper does not send the X-Prometheus-Scrape-Timeout-Seconds header.
+    pub(crate) scrape_timeout_ms: u64,
+    /// Exposes the time each target was crawled as the timestamps of its samples.
+    pub(crate) timestamps: bool,
 }
 
 impl Default for Server {
     fn default() -> Self {
         Server {
+            enabled: true,
             listen_addr: "127.0.0.1:9090".to_string(),
+            scrape_timeout_ms: 10000,
+            timestamps: false,
         }
     }
 }
 
+/// Pushing the metrics to a Prometheus Pushgateway periodically.
+///
+/// The metrics are pushed into the group of the job and instance grouping keys,
+/// replacing the ones of the previous push.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Pushgateway {
+    /// The base URL of the Pushgateway, e.g. http://127.0.0.1:9091
+    pub(crate) url: String,
+    pub(crate) job: String,
+    /// The instance grouping key, left out if empty.
+    pub(crate) instance: String,
+    pub(crate) interval_ms: u64,
+    pub(crate) timeout_ms: u64,
+    pub(crate) basic_auth: Option<BasicAuth>,
+    /// Deletes the group from the Pushgateway when the exporter shuts down.
+    pub(crate) delete_on_shutdown: bool,
+}
+
+impl Default for Pushgateway {
+    fn default() -> Self {
+        Pushgateway {
+            url: "http://127.0.0.1:9091".to_string(),
+            job: "findora_exporter".to_string(),
+            instance: "".to_string(),
+            interval_ms: 15000,
+            timeout_ms: 10000,
+            basic_auth: None,
+            delete_on_shutdown: false,
+        }
+    }
+}
+
+/// Sending the samples to a Prometheus remote_write endpoint periodically.
+///
+/// Batches which failed to send are kept in memory and resent in order,
+/// the oldest ones are dropped once max_pending batches are waiting.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct RemoteWrite {
+    /// The URL of the endpoint, e.g. http://127.0.0.1:9009/api/v1/push
+    pub(crate) url: String,
+    pub(crate) interval_ms: u64,
+    /// The timeouts and the retry policy of sending a batch.
+    pub(crate) http: Http,
+    pub(crate) basic_auth: Option<BasicAuth>,
+    pub(crate) max_pending: usize,
+}
+
+impl Default for RemoteWrite {
+    fn default() -> Self {
+        RemoteWrite {
+            url: "http://127.0.0.1:9009/api/v1/push".to_string(),
+            interval_ms: 15000,
+            http: Http::default(),
+            basic_auth: None,
+            max_pending: 240,
+        }
+    }
+}
+
+/// An output sink emitting the values of every successful crawl as lines of its format.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Sink {
+    pub(crate) format: SinkFormat,
+    pub(crate) protocol: SinkProtocol,
+    /// The address of the receiver, e.g. 127.0.0.1:2003
+    pub(crate) addr: String,
+    /// The dotted path prepended to every metric name, nothing if empty.
+    pub(crate) prefix: String,
+    pub(crate) labels: LabelStyle,
+    /// How long connecting and writing to a TCP receiver can take.
+    pub(crate) timeout_ms: u64,
+}
+
+impl Default for Sink {
+    fn default() -> Self {
+        Sink {
+            format: SinkFormat::default(),
+            protocol: SinkProtocol::default(),
+            addr: "127.0.0.1:2003".to_string(),
+            prefix: "".to_string(),
+            labels: LabelStyle::default(),
+            timeout_ms: 1000,
+        }
+    }
+}
+
+/// The line format of a sink.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum SinkFormat {
+    /// The Graphite plaintext protocol, `path value timestamp`.
+    #[default]
+    Graphite,
+    /// StatsD gauges, `name:value|g`.
+    Statsd,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum SinkProtocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+/// The way the labels of a series are written by a sink.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum LabelStyle {
+    /// Appended to the metric name as dotted `name.value` pairs.
+    #[default]
+    Path,
+    /// Written as Graphite tags (`;name=value`) or DogStatsD tags (`|#name:value`).
+    Tags,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct BasicAuth {
+    pub(crate) username: String,
+    pub(crate) password: String,
+}
+
+impl BasicAuth {
+    /// Returns the value of the Authorization header of the credentials.
+    pub(crate) fn header(&self) -> String {
+        let credentials = format!("{}:{}", self.username, self.password);
+        format!("Basic {}", general_purpose::STANDARD.encode(credentials))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -168,6 +581,26 @@ mod tests {
         assert_eq!(want, got);
     }
 
+    #[test]
+    fn test_read_config_zero_frequency() {
+        let tmp_dir = TmpDir::new(format!(
+            "{}/findora_exporter_test_read_config_zero_frequency",
+            env::temp_dir().display()
+        ))
+        .unwrap();
+        let cfg_path = PathBuf::from(&format!("{}/config.json", tmp_dir.path().display()));
+
+        let mut cfg = Config::default();
+        cfg.crawler.targets[0].frequency_ms = Some(0);
+        fs::write(cfg_path.as_path(), serde_json::to_string(&cfg).unwrap()).unwrap();
+        assert!(read_config(cfg_path.as_path()).is_err());
+
+        let mut cfg = Config::default();
+        cfg.crawler.frequency_ms = 0;
+        fs::write(cfg_path.as_path(), serde_json::to_string(&cfg).unwrap()).unwrap();
+        assert!(read_config(cfg_path.as_path()).is_err());
+    }
+
     #[test]
     fn test_read_config() {
         let tmp_dir = TmpDir::new(format!(
@@ -179,6 +612,28 @@ mod tests {
 
         let mut want = Config::default();
         want.server.listen_addr = "0.0.0.0:33456".to_string();
+        want.pushgateway = Some(Pushgateway {
+            instance: "bridge-1".to_string(),
+            basic_auth: Some(BasicAuth {
+                username: "findora".to_string(),
+                password: "secret".to_string(),
+            }),
+            delete_on_shutdown: true,
+            ..Default::default()
+        });
+        want.remote_write = Some(RemoteWrite {
+            url: "https://mimir.example.com/api/v1/push".to_string(),
+            max_pending: 10,
+            ..Default::default()
+        });
+        want.sinks.push(Sink {
+            format: SinkFormat::Statsd,
+            protocol: SinkProtocol::Udp,
+            addr: "127.0.0.1:8125".to_string(),
+            prefix: "findora".to_string(),
+            labels: LabelStyle::Tags,
+            ..Default::default()
+        });
         let mut labels = HashMap::new();
         labels.insert("env".to_string(), "dev".to_string());
         want.crawler.targets.push(Target {
@@ -189,6 +644,19 @@ mod tests {
                 prefix: "findora_exporter".to_string(),
                 labels,
             }),
+            frequency_ms: Some(60000),
+            http: Some(Http {
+                max_retries: 5,
+                ..Default::default()
+            }),
+            fallback_addrs: vec!["https://elsewhere.com/metrics:443".to_string()],
+            endpoint_mode: EndpointMode::Quorum,
+            quorum: 2,
+            max_age_ms: Some(300000),
+            stale_policy: StalePolicy::Label,
+            metric_name: Some("bridge_reserve".to_string()),
+            help: Some("the reserve of the bridge on BSC".to_string()),
+            unit: Some("usdt".to_string()),
         });
 
         let json = serde_json::to_string(&want).unwrap();
@@ -196,5 +664,20 @@ mod tests {
 
         let got = read_config(cfg_path.as_path()).unwrap();
         assert_eq!(want, got);
+
+        // targets only compare host_addr and task_name
+        let (want, got) = (&want.crawler.targets[1], &got.crawler.targets[1]);
+        assert_eq!(want.extra_opts, got.extra_opts);
+        assert_eq!(want.registry, got.registry);
+        assert_eq!(want.frequency_ms, got.frequency_ms);
+        assert_eq!(want.http, got.http);
+        assert_eq!(want.fallback_addrs, got.fallback_addrs);
+        assert_eq!(want.endpoint_mode, got.endpoint_mode);
+        assert_eq!(want.quorum, got.quorum);
+        assert_eq!(want.max_age_ms, got.max_age_ms);
+        assert_eq!(want.stale_policy, got.stale_policy);
+        assert_eq!(want.metric_name, got.metric_name);
+        assert_eq!(want.help, got.help);
+        assert_eq!(want.unit, got.unit);
     }
 }
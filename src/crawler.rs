@@ -1,131 +1,131 @@
 use std::{
     sync::{
         atomic::{AtomicBool, Ordering},
-        mpsc::channel,
-        Arc, Mutex,
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        Arc, Condvar, Mutex, RwLock,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    config::{ExtraOpts, TaskName},
-    metrics::Metric,
+    client::{CircuitOpen, Clients},
+    config::{CrawlMode, ExtraOpts},
+    endpoints::Endpoints,
+    metrics::{task_labels, Metric, Metrics},
+    sinks::Sinks,
+    tasks::TaskFn,
     utils::calculate_hash,
 };
 
 use anyhow::{Context, Result};
-use log::error;
-use prometheus::core::Atomic;
+use log::{debug, error, warn};
 
 /// A collection of Workers for managing easily.
 pub(crate) struct Crawler {
     workers: Vec<Option<thread::JoinHandle<()>>>,
-    done: Arc<AtomicBool>,
+    shutdown: Arc<Shutdown>,
+    grace: Duration,
+    scraper: Option<Arc<dyn Scraper>>,
+    tasks: Arc<RwLock<Vec<Arc<Task>>>>,
+    clients: Clients,
+    metrics: Arc<Metrics>,
+    sinks: Arc<Sinks>,
+}
+
+/// Something running every target on demand.
+pub(crate) trait Scraper: Send + Sync {
+    /// Runs every target and waits for them at most timeout.
+    fn scrape(&self, timeout: Duration);
 }
 
 impl Crawler {
     /// Returns a Crawler instance and
     /// Spawned
-    /// 1. a thread to push tasks into a mpsc queue.
+    /// 1. a thread to push tasks into a bounded mpsc queue.
     /// 2. N threads of worker to consume tasks from the mpsc queue.
-    pub(crate) fn new<T>(
+    ///
+    /// A task is not pushed again until its previous run has finished,
+    /// every run skipped this way is counted in the skipped ticks metric.
+    /// So the queue holds every task at most once and pushing into a full queue waits for a slot,
+    /// a scrape of the OnDemand mode waits at most until its timeout.
+    ///
+    /// Workers stop once the task pusher stops and drops the sending side of the queue.
+    ///
+    /// In the OnDemand mode the task pusher pushes nothing by itself,
+    /// tasks are pushed by the Scraper of the crawler instead.
+    ///
+    /// The values of every successful run are emitted to the sinks as well.
+    pub(crate) fn new(
         cfg: &crate::config::Crawler,
-        metrics: Arc<crate::metrics::Metrics<T>>,
-    ) -> Result<Self>
-    where
-        T: Atomic + 'static,
-    {
+        metrics: Arc<Metrics>,
+        sinks: Arc<Sinks>,
+    ) -> Result<Self> {
         let mut workers = Vec::with_capacity(cfg.worker_n + 1);
-        let mut tasks = Vec::with_capacity(cfg.targets.len());
-        let done = Arc::new(AtomicBool::new(false));
-        let (tx, rx) = channel();
+        let shutdown = Arc::new(Shutdown::default());
+        let (tx, rx) = sync_channel(cfg.queue_size);
         let rx = Arc::new(Mutex::new(rx));
+        let finished = Arc::new(Finished::default());
+        let dispatcher = Arc::new(Dispatcher {
+            tx: Mutex::new(Some(tx)),
+            metrics: metrics.clone(),
+        });
 
-        for target in &cfg.targets {
-            let metric = metrics
-                .get_metric(calculate_hash(&target))
-                .expect("get_metric failed");
-
-            let task = match target.task_name {
-                TaskName::ConsensusPower => Task::new(
-                    "consensus_power".to_string(),
-                    target.host_addr.clone(),
-                    metric,
-                    target.extra_opts.clone(),
-                    crate::tasks::consensus_power,
-                ),
-                TaskName::NetworkFunctional => Task::new(
-                    "network_functional".to_string(),
-                    target.host_addr.clone(),
-                    metric,
-                    target.extra_opts.clone(),
-                    crate::tasks::network_functional,
-                ),
-                TaskName::TotalCountOfValidators => Task::new(
-                    "total_count_of_validators".to_string(),
-                    target.host_addr.clone(),
-                    metric,
-                    target.extra_opts.clone(),
-                    crate::tasks::total_count_of_validators,
-                ),
-                TaskName::TotalBalanceOfRelayers => Task::new(
-                    "total_balance_of_relayers".to_string(),
-                    target.host_addr.clone(),
-                    metric,
-                    target.extra_opts.clone(),
-                    crate::tasks::total_balance_of_relayers,
-                ),
-                TaskName::BridgedBalance => Task::new(
-                    "bridged_balance".to_string(),
-                    target.host_addr.clone(),
-                    metric,
-                    target.extra_opts.clone(),
-                    crate::tasks::bridged_balance,
-                ),
-                TaskName::BridgedSupply => Task::new(
-                    "bridged_supply".to_string(),
-                    target.host_addr.clone(),
-                    metric,
-                    target.extra_opts.clone(),
-                    crate::tasks::bridged_supply,
-                ),
-                TaskName::NativeBalance => Task::new(
-                    "native_balance".to_string(),
-                    target.host_addr.clone(),
-                    metric,
-                    target.extra_opts.clone(),
-                    crate::tasks::native_balance,
-                ),
-                TaskName::GetPrice => Task::new(
-                    "get_price".to_string(),
-                    target.host_addr.clone(),
-                    metric,
-                    target.extra_opts.clone(),
-                    crate::tasks::get_price,
-                ),
-            };
-
-            tasks.push(Arc::new(task));
-        }
+        let clients = Clients::new(&cfg.circuit_breaker, metrics.circuit_breaker_states());
+        let tasks = build_tasks(cfg, &clients, &metrics, &sinks, &[])?;
+        let tasks = Arc::new(RwLock::new(tasks));
+        let scraper: Option<Arc<dyn Scraper>> = match cfg.mode {
+            CrawlMode::Interval => None,
+            CrawlMode::OnDemand => Some(Arc::new(Trigger {
+                tasks: tasks.clone(),
+                dispatcher: dispatcher.clone(),
+                finished: finished.clone(),
+                cache_ttl: Duration::from_millis(cfg.cache_ttl_ms),
+                last_scrape: Mutex::new(None),
+            })),
+        };
 
-        let freq = Duration::from_millis(cfg.frequency_ms);
-        let tx_done = done.clone();
+        // the pusher sleeps this long when there is nothing to schedule
+        let idle = Duration::from_millis(cfg.frequency_ms);
+        let mode = cfg.mode;
+        let tx_shutdown = shutdown.clone();
+        let tx_tasks = tasks.clone();
         workers.push(Some(
             thread::Builder::new()
                 .name("task pusher".to_string())
                 .spawn(move || {
-                    while !tx_done.load(Ordering::SeqCst) {
-                        for task in tasks.clone() {
-                            if let Err(e) = tx.send(task.clone()) {
-                                error!(
-                                    "task pusher sending task:{}, addr:{} failed:{}",
-                                    task.name, task.addr, e
-                                );
+                    if mode == CrawlMode::Interval {
+                        loop {
+                            let now = Instant::now();
+                            // a reload does not wait for the pusher waiting on a full queue
+                            let tasks = tx_tasks.read().expect("read tasks failed").clone();
+                            for task in tasks.iter().filter(|t| t.is_due(now)) {
+                                let missed = task.reschedule(now);
+                                if missed > 0 {
+                                    dispatcher.metrics.skip_ticks(
+                                        task.endpoints.labels(),
+                                        "missed",
+                                        missed,
+                                    );
+                                }
+                                dispatcher.dispatch(task, None);
+                            }
+
+                            let next_run = tasks
+                                .iter()
+                                .map(|t| t.next_run())
+                                .min()
+                                .unwrap_or_else(|| now + idle);
+                            if tx_shutdown.wait(next_run.saturating_duration_since(Instant::now()))
+                            {
+                                break;
                             }
                         }
-                        thread::sleep(freq);
                     }
+
+                    // the on-demand mode only waits for shutdown
+                    while !tx_shutdown.wait(idle) {}
+                    dispatcher.close();
                 })
                 .context("spawning task pusher thread failed")?,
         ));
@@ -133,112 +133,920 @@ impl Crawler {
         for id in 0..cfg.worker_n {
             let rx = rx.clone();
             let name = format!("worker{}", id);
-            let rx_done = done.clone();
+            let rx_shutdown = shutdown.clone();
+            let finished = finished.clone();
             workers.push(Some(
                 thread::Builder::new()
                     .name(name.clone())
-                    .spawn(move || {
-                        while !rx_done.load(Ordering::SeqCst) {
-                            match rx.lock() {
-                                Ok(r) => match r.recv() {
-                                    Ok(task) => {
-                                        drop(r);
-                                        task.execute()
-                                    }
-                                    Err(e) => error!("{} recv failed:{}", name, e),
-                                },
-                                Err(e) => error!("{} lock rx failed:{}", name, e),
+                    .spawn(move || loop {
+                        let task = match rx.lock() {
+                            Ok(r) => r.recv(),
+                            Err(e) => {
+                                error!("{} lock rx failed:{}", name, e);
+                                break;
                             }
+                        };
+
+                        // the queue only disconnects when the task pusher has stopped
+                        let task = match task {
+                            Ok(task) => task,
+                            Err(_) => break,
+                        };
+
+                        if !rx_shutdown.is_done() {
+                            task.execute();
                         }
+                        task.finish();
+                        finished.notify();
                     })
                     .context("spawning worker thread failed")?,
             ));
         }
 
-        Ok(Crawler { workers, done })
+        Ok(Crawler {
+            workers,
+            shutdown,
+            grace: Duration::from_millis(cfg.shutdown_grace_ms),
+            scraper,
+            tasks,
+            clients,
+            metrics,
+            sinks,
+        })
+    }
+
+    /// Replaces the targets of the crawler with the targets of cfg.
+    ///
+    /// Unchanged targets keep running on their schedule,
+    /// new or changed targets run right away and removed targets stop running.
+    /// The metrics have to be reloaded with the same cfg beforehand.
+    ///
+    /// Only the targets and the crawler's frequency_ms, http, legacy_scaling and auto_labels are reloaded,
+    /// the other settings of the crawler need a restart.
+    pub(crate) fn reload(&self, cfg: &crate::config::Crawler) -> Result<()> {
+        let mut tasks = self.tasks.write().expect("write tasks failed");
+        *tasks = build_tasks(cfg, &self.clients, &self.metrics, &self.sinks, &tasks)?;
+        drop(tasks);
+        self.clients.retain(cfg.targets.iter().flat_map(|t| {
+            let def = crate::tasks::definition(t.task_name);
+            std::iter::once(&t.host_addr)
+                .chain(&t.fallback_addrs)
+                .map(move |addr| def.upstream(addr))
+        }));
+
+        // lets the task pusher reschedule with the new tasks
+        self.shutdown.wake();
+        Ok(())
+    }
+
+    /// Returns the Scraper running targets on demand, None if not in the OnDemand mode.
+    pub(crate) fn scraper(&self) -> Option<Arc<dyn Scraper>> {
+        self.scraper.clone()
     }
 
     /// Signaling workers to stop working.
+    ///
+    /// Waits at most the shutdown grace period for workers which are still running a task,
+    /// those workers are left behind to be torn down along with the process.
     pub(crate) fn close(&mut self) {
-        self.done.store(true, Ordering::SeqCst);
+        self.shutdown.signal();
 
+        let deadline = Instant::now() + self.grace;
         for worker in self.workers.iter_mut() {
             if let Some(w) = worker.take() {
-                let _ = w.join();
+                while !w.is_finished() && Instant::now() < deadline {
+                    thread::sleep(Duration::from_millis(10));
+                }
+
+                if w.is_finished() {
+                    let _ = w.join();
+                } else {
+                    warn!(
+                        "{} did not stop within the shutdown grace period",
+                        w.thread().name().unwrap_or("crawler thread")
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// The sending side of the task queue.
+struct Dispatcher {
+    tx: Mutex<Option<SyncSender<Arc<Task>>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Dispatcher {
+    /// Pushes the task into the queue unless its previous run is still in flight,
+    /// which is counted in the skipped ticks metric.
+    ///
+    /// Waits for a slot if the queue is full, skipping right away would starve
+    /// the tasks which always come last once there are more tasks than slots.
+    /// With a deadline it waits at most until then and skips the task afterwards,
+    /// which is counted in the skipped ticks metric as well.
+    fn dispatch(&self, task: &Arc<Task>, deadline: Option<Instant>) {
+        if !task.start() {
+            warn!(
+                "task pusher skipping task:{}, addr:{}, previous run still in flight",
+                task.name,
+                task.addr()
+            );
+            self.metrics
+                .skip_ticks(task.endpoints.labels(), "in_flight", 1);
+            return;
+        }
+
+        let mut task = task.clone();
+        loop {
+            let guard = self.tx.lock().expect("lock tx failed");
+            let tx = match guard.as_ref() {
+                Some(tx) => tx,
+                None => {
+                    task.finish();
+                    return;
+                }
+            };
+
+            let result = match deadline {
+                Some(_) => tx.try_send(task),
+                None => tx.send(task).map_err(|e| TrySendError::Disconnected(e.0)),
+            };
+            match result {
+                Ok(()) => return,
+                Err(TrySendError::Full(t)) if deadline.is_some_and(|d| Instant::now() < d) => {
+                    // lets the others push while waiting for a slot
+                    drop(guard);
+                    task = t;
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(TrySendError::Full(task)) => {
+                    task.finish();
+                    warn!(
+                        "task pusher skipping task:{}, addr:{}, queue is still full at the deadline",
+                        task.name,
+                        task.addr()
+                    );
+                    self.metrics
+                        .skip_ticks(task.endpoints.labels(), "queue_full", 1);
+                    return;
+                }
+                Err(TrySendError::Disconnected(task)) => {
+                    task.finish();
+                    error!(
+                        "task pusher sending task:{}, addr:{} failed: disconnected",
+                        task.name,
+                        task.addr()
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Drops the sending side of the queue for stopping workers.
+    fn close(&self) {
+        self.tx.lock().expect("lock tx failed").take();
+    }
+}
+
+/// The Scraper of the OnDemand mode.
+///
+/// Scrapes within cache_ttl of the previous one do not push tasks again
+/// but still wait for the tasks in flight, which prevents stampedes on targets.
+struct Trigger {
+    tasks: Arc<RwLock<Vec<Arc<Task>>>>,
+    dispatcher: Arc<Dispatcher>,
+    finished: Arc<Finished>,
+    cache_ttl: Duration,
+    last_scrape: Mutex<Option<Instant>>,
+}
+
+impl Scraper for Trigger {
+    fn scrape(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let expired = {
+            let mut last_scrape = self.last_scrape.lock().expect("lock last_scrape failed");
+            match *last_scrape {
+                Some(t) if t.elapsed() < self.cache_ttl => false,
+                _ => {
+                    *last_scrape = Some(Instant::now());
+                    true
+                }
+            }
+        };
+
+        let tasks = self.tasks.read().expect("read tasks failed").clone();
+        if expired {
+            for task in tasks.iter() {
+                self.dispatcher.dispatch(task, Some(deadline));
             }
         }
+
+        if !self
+            .finished
+            .wait_until(deadline, || tasks.iter().all(|t| !t.is_in_flight()))
+        {
+            warn!(
+                "scrape timed out after {:?}, serving the previous values of the unfinished tasks",
+                timeout
+            );
+        }
     }
 }
 
-#[derive(Clone)]
-struct Task<T: Atomic> {
+/// A notification of workers finishing tasks.
+#[derive(Default)]
+struct Finished {
+    lock: Mutex<()>,
+    cvar: Condvar,
+}
+
+impl Finished {
+    fn notify(&self) {
+        let _guard = self.lock.lock().expect("lock finished failed");
+        self.cvar.notify_all();
+    }
+
+    /// Sleeps until the condition holds or the deadline passes,
+    /// returns whether the condition holds.
+    fn wait_until<F>(&self, deadline: Instant, condition: F) -> bool
+    where
+        F: Fn() -> bool,
+    {
+        let mut guard = self.lock.lock().expect("lock finished failed");
+        while !condition() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            guard = self
+                .cvar
+                .wait_timeout(guard, deadline - now)
+                .expect("wait finished failed")
+                .0;
+        }
+        true
+    }
+}
+
+/// A shutdown flag which wakes up threads sleeping on it.
+#[derive(Default)]
+pub(crate) struct Shutdown {
+    done: Mutex<bool>,
+    cvar: Condvar,
+}
+
+impl Shutdown {
+    pub(crate) fn signal(&self) {
+        *self.done.lock().expect("lock shutdown failed") = true;
+        self.cvar.notify_all();
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        *self.done.lock().expect("lock shutdown failed")
+    }
+
+    /// Wakes up threads sleeping on it without signaling.
+    fn wake(&self) {
+        let _done = self.done.lock().expect("lock shutdown failed");
+        self.cvar.notify_all();
+    }
+
+    /// Sleeps for the timeout or until signaled or woken up,
+    /// returns true if signaled.
+    ///
+    /// It may also return early on a spurious wakeup,
+    /// so the callers have to check their own conditions again.
+    fn wait(&self, timeout: Duration) -> bool {
+        let done = self.done.lock().expect("lock shutdown failed");
+        if *done {
+            return true;
+        }
+        let (done, _) = self
+            .cvar
+            .wait_timeout(done, timeout)
+            .expect("wait shutdown failed");
+        *done
+    }
+
+    /// Sleeps for the whole timeout unless signaled,
+    /// returns true if signaled.
+    pub(crate) fn sleep(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return self.is_done();
+            }
+            if self.wait(deadline - now) {
+                return true;
+            }
+        }
+    }
+}
+
+/// Returns the tasks of the targets of cfg,
+/// reusing the current tasks of unchanged targets.
+///
+/// A task is only reused while it still updates the Metric of its target,
+/// the metrics replace the Metric on settings the task does not know about such as legacy_scaling.
+fn build_tasks(
+    cfg: &crate::config::Crawler,
+    clients: &Clients,
+    metrics: &Metrics,
+    sinks: &Arc<Sinks>,
+    current: &[Arc<Task>],
+) -> Result<Vec<Arc<Task>>> {
+    let now = Instant::now();
+    let mut tasks = Vec::with_capacity(cfg.targets.len());
+    for target in &cfg.targets {
+        let interval = Duration::from_millis(target.frequency_ms.unwrap_or(cfg.frequency_ms));
+        let http = target.http.as_ref().unwrap_or(&cfg.http);
+        let fingerprint = calculate_hash(&(target.fingerprint(), interval, http));
+        let metric = metrics
+            .get_metric(calculate_hash(target))
+            .with_context(|| format!("no metric of target: {:?}", target))?;
+        if let Some(task) = current
+            .iter()
+            .find(|t| t.fingerprint == fingerprint && Arc::ptr_eq(&t.metric, &metric))
+        {
+            tasks.push(task.clone());
+            continue;
+        }
+
+        let name = target.task_name.name();
+        let def = crate::tasks::definition(target.task_name);
+        let f = def.run;
+
+        // the clients are shared by the host the task actually requests
+        let endpoints = std::iter::once(&target.host_addr)
+            .chain(&target.fallback_addrs)
+            .map(|addr| (addr.clone(), clients.get(def.upstream(addr), http)))
+            .collect();
+        let endpoints = Endpoints::new(
+            task_labels(target),
+            endpoints,
+            target.endpoint_mode,
+            target.quorum,
+            Some(metrics.endpoint_metrics()),
+        );
+
+        let mut task = Task::new(
+            name.to_string(),
+            endpoints,
+            metric,
+            target.extra_opts.clone(),
+            f,
+            interval,
+            now,
+        );
+        task.fingerprint = fingerprint;
+        task.sinks = sinks.clone();
+        tasks.push(Arc::new(task));
+    }
+    Ok(tasks)
+}
+
+struct Task {
     name: String,
-    addr: String,
-    metric: Arc<Metric<T>>,
+    endpoints: Endpoints,
+    metric: Arc<Metric>,
     option: Option<ExtraOpts>,
-    f: fn(&str, &Option<ExtraOpts>) -> Result<<T as Atomic>::T>,
+    f: TaskFn,
+    interval: Duration,
+    next_run: Mutex<Instant>,
+    in_flight: AtomicBool,
+    /// identifies the whole configuration of the task's target.
+    fingerprint: u64,
+    sinks: Arc<Sinks>,
 }
 
-impl<T> Task<T>
-where
-    T: Atomic + 'static,
-{
+impl Task {
     fn new(
         name: String,
-        addr: String,
-        metric: Arc<Metric<T>>,
+        endpoints: Endpoints,
+        metric: Arc<Metric>,
         option: Option<ExtraOpts>,
-        f: fn(&str, &Option<ExtraOpts>) -> Result<<T as Atomic>::T>,
+        f: TaskFn,
+        interval: Duration,
+        first_run: Instant,
     ) -> Self {
         Task {
             name,
-            addr,
+            endpoints,
             metric,
             option,
             f,
+            interval,
+            next_run: Mutex::new(first_run),
+            in_flight: AtomicBool::new(false),
+            fingerprint: 0,
+            sinks: Arc::new(Sinks::default()),
         }
     }
 
     fn execute(&self) {
-        match (self.f)(&self.addr, &self.option) {
-            Ok(v) => self.metric.set(v),
+        let start = Instant::now();
+        let result = self
+            .endpoints
+            .run(|client, addr| (self.f)(client, addr, &self.option));
+        self.metric.record(
+            start.elapsed(),
+            result.as_ref().err().map(|e| format!("{:#}", e)),
+        );
+
+        match result {
+            Ok(samples) => {
+                self.metric.update(&samples);
+                self.sinks.emit(&self.metric.points(&samples));
+            }
+            // the breaker already warned when it opened
+            Err(e) if e.is::<CircuitOpen>() => {
+                debug!("task:{}, addr:{}, skipped: {}", self.name, self.addr(), e)
+            }
             Err(e) => error!(
                 "task:{}, addr:{}, option:{:?}, err:{}",
-                self.name, self.addr, self.option, e
+                self.name,
+                self.addr(),
+                self.option,
+                e
             ),
         }
     }
+
+    /// Returns the primary endpoint identifying the task's target.
+    fn addr(&self) -> &str {
+        self.endpoints.primary()
+    }
+
+    fn next_run(&self) -> Instant {
+        *self.next_run.lock().expect("lock next_run failed")
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        self.next_run() <= now
+    }
+
+    /// Moves the next run time forward by one interval.
+    ///
+    /// If the pusher has fallen behind more than one interval,
+    /// the missed runs are dropped instead of being fired back to back,
+    /// returns the number of dropped runs.
+    fn reschedule(&self, now: Instant) -> u64 {
+        let mut next_run = self.next_run.lock().expect("lock next_run failed");
+        *next_run += self.interval;
+        if *next_run > now {
+            return 0;
+        }
+
+        let behind = now.duration_since(*next_run).as_nanos() / self.interval.as_nanos().max(1);
+        *next_run = now + self.interval;
+        u64::try_from(behind).unwrap_or(u64::MAX).saturating_add(1)
+    }
+
+    /// Marks the task as in flight,
+    /// returns false if the previous run has not finished yet.
+    fn start(&self) -> bool {
+        !self.in_flight.swap(true, Ordering::SeqCst)
+    }
+
+    fn finish(&self) {
+        self.in_flight.store(false, Ordering::SeqCst)
+    }
+
+    fn is_in_flight(&self) -> bool {
+        self.in_flight.load(Ordering::SeqCst)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
+        client::Client,
         config::{Crawler as CrawlerConfig, Target as TargetConfig, TaskName},
-        metrics::Metrics,
+        test_util::serve,
     };
-    use prometheus::core::AtomicU64;
-    use std::{thread::sleep, time::Duration};
+    use std::{net::TcpListener, sync::atomic::AtomicUsize, thread::sleep, time::Duration};
+
+    /// Serves a tendermint response answering both /status and /validators,
+    /// returns the served address and the count of received requests.
+    fn serve_status() -> (String, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let c = count.clone();
+        let addr = serve(move |_| {
+            c.fetch_add(1, Ordering::SeqCst);
+            let body = r#"{"result":{"total":"7","sync_info":{"latest_block_time":"2022-01-14T13:44:55.889015796Z","latest_block_height":"42"}}}"#;
+            tiny_http::Response::from_string(body).boxed()
+        });
+        (addr, count)
+    }
 
     #[test]
     fn test_crawler_should_worked() {
+        let (addr, _) = serve_status();
         let cfg = CrawlerConfig {
             targets: vec![TargetConfig {
-                host_addr: "https://prod-mainnet.prod.findora.org:26657".to_string(),
+                host_addr: addr,
                 task_name: TaskName::TotalCountOfValidators,
-                registry: None,
-                extra_opts: None,
+                ..Default::default()
             }],
             worker_n: 1,
             frequency_ms: 300,
+            ..Default::default()
         };
-        let m = Arc::new(Metrics::<AtomicU64>::new(&cfg).unwrap());
-        let mut c = Crawler::new(&cfg, m.clone()).unwrap();
+        let m = Arc::new(Metrics::new(&cfg).unwrap());
+        let mut c = Crawler::new(&cfg, m.clone(), Arc::default()).unwrap();
         sleep(Duration::from_secs(1));
         c.close();
 
+        // the exporter's own families are gathered as well
         let got = m.gather();
-        assert_eq!(1, got.len());
-        assert_ne!(0.0, got[0].get_metric()[0].get_gauge().get_value());
+        let got = got
+            .iter()
+            .find(|f| f.get_name() == "total_count_of_validators")
+            .unwrap();
+        assert_eq!(7.0, got.get_metric()[0].get_gauge().get_value());
+    }
+
+    #[test]
+    fn test_crawler_close_does_not_wait_for_hung_tasks() {
+        // accepts connections into the backlog but never answers them
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cfg = CrawlerConfig {
+            targets: vec![TargetConfig {
+                host_addr: format!("http://{}", listener.local_addr().unwrap()),
+                task_name: TaskName::NetworkFunctional,
+                ..Default::default()
+            }],
+            worker_n: 2,
+            frequency_ms: 60000,
+            shutdown_grace_ms: 200,
+            ..Default::default()
+        };
+        let m = Arc::new(Metrics::new(&cfg).unwrap());
+        let mut c = Crawler::new(&cfg, m, Arc::default()).unwrap();
+        sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        c.close();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_crawler_on_demand() {
+        let (addr, count) = serve_status();
+        let cfg = CrawlerConfig {
+            targets: vec![TargetConfig {
+                host_addr: addr,
+                task_name: TaskName::NetworkFunctional,
+                ..Default::default()
+            }],
+            worker_n: 2,
+            frequency_ms: 10,
+            mode: CrawlMode::OnDemand,
+            cache_ttl_ms: 60000,
+            ..Default::default()
+        };
+        let m = Arc::new(Metrics::new(&cfg).unwrap());
+        let mut c = Crawler::new(&cfg, m.clone(), Arc::default()).unwrap();
+        let scraper = c.scraper().unwrap();
+
+        // nothing runs on a timer
+        sleep(Duration::from_millis(100));
+        assert_eq!(0, count.load(Ordering::SeqCst));
+
+        scraper.scrape(Duration::from_secs(5));
+        assert_eq!(1, count.load(Ordering::SeqCst));
+        let got = m.gather();
+        let got = got
+            .iter()
+            .find(|f| f.get_name() == "network_functional")
+            .unwrap();
+        assert_ne!(0.0, got.get_metric()[0].get_gauge().get_value());
+
+        // the cached values are served within cache_ttl_ms
+        scraper.scrape(Duration::from_secs(5));
+        assert_eq!(1, count.load(Ordering::SeqCst));
+
+        c.close();
+
+        let cfg = CrawlerConfig {
+            targets: vec![],
+            ..Default::default()
+        };
+        let mut c = Crawler::new(&cfg, m, Arc::default()).unwrap();
+        assert!(c.scraper().is_none());
+        c.close();
+    }
+
+    #[test]
+    fn test_crawler_more_tasks_than_queue_size() {
+        let servers: Vec<_> = (0..8).map(|_| serve_status()).collect();
+        let cfg = CrawlerConfig {
+            targets: servers
+                .iter()
+                .map(|(addr, _)| TargetConfig {
+                    host_addr: addr.clone(),
+                    task_name: TaskName::NetworkFunctional,
+                    ..Default::default()
+                })
+                .collect(),
+            worker_n: 1,
+            queue_size: 1,
+            mode: CrawlMode::OnDemand,
+            cache_ttl_ms: 0,
+            ..Default::default()
+        };
+        let m = Arc::new(Metrics::new(&cfg).unwrap());
+        let mut c = Crawler::new(&cfg, m, Arc::default()).unwrap();
+        let scraper = c.scraper().unwrap();
+
+        // every target runs on every scrape, not only the ones fitting into the queue
+        for n in 1..=2 {
+            scraper.scrape(Duration::from_secs(5));
+            for (_, count) in &servers {
+                assert_eq!(n, count.load(Ordering::SeqCst));
+            }
+        }
+        c.close();
+    }
+
+    #[test]
+    fn test_crawler_scrape_waits_for_queue_at_most_timeout() {
+        let slow = || {
+            serve(|_| {
+                sleep(Duration::from_millis(500));
+                tiny_http::Response::from_string("{}").boxed()
+            })
+        };
+        let cfg = CrawlerConfig {
+            targets: (0..4)
+                .map(|_| TargetConfig {
+                    host_addr: slow(),
+                    task_name: TaskName::NetworkFunctional,
+                    ..Default::default()
+                })
+                .collect(),
+            worker_n: 1,
+            queue_size: 1,
+            mode: CrawlMode::OnDemand,
+            ..Default::default()
+        };
+        let m = Arc::new(Metrics::new(&cfg).unwrap());
+        let mut c = Crawler::new(&cfg, m.clone(), Arc::default()).unwrap();
+
+        let start = Instant::now();
+        c.scraper().unwrap().scrape(Duration::from_millis(100));
+        assert!(start.elapsed() < Duration::from_millis(400));
+
+        // the tasks neither running nor queued are skipped
+        let skipped: u64 = m
+            .gather()
+            .iter()
+            .filter(|f| f.get_name() == "findora_exporter_skipped_ticks_total")
+            .flat_map(|f| f.get_metric())
+            .map(|m| m.get_counter().get_value() as u64)
+            .sum();
+        assert_eq!(2, skipped);
+        c.close();
+    }
+
+    #[test]
+    fn test_crawler_shares_clients_by_upstream() {
+        let target = |addr: &str, pair: &str| TargetConfig {
+            host_addr: addr.to_string(),
+            task_name: TaskName::GetPrice,
+            extra_opts: Some(ExtraOpts::GetPrice {
+                currency_pair: pair.to_string(),
+                currency_name: "FRA".to_string(),
+            }),
+            ..Default::default()
+        };
+        let cfg = CrawlerConfig {
+            targets: vec![
+                target("TEST_FRA_USDT", "FRA_USDT"),
+                target("TEST_FRA_ETH", "FRA_ETH"),
+            ],
+            mode: CrawlMode::OnDemand,
+            ..Default::default()
+        };
+        let m = Arc::new(Metrics::new(&cfg).unwrap());
+        let mut c = Crawler::new(&cfg, m.clone(), Arc::default()).unwrap();
+
+        // the placeholders of host_addr share the breaker of the upstream
+        let hosts: Vec<String> = m
+            .gather()
+            .iter()
+            .filter(|f| f.get_name() == "findora_exporter_circuit_breaker_state")
+            .flat_map(|f| f.get_metric())
+            .flat_map(|m| m.get_label())
+            .map(|l| l.get_value().to_string())
+            .collect();
+        assert_eq!(vec!["https://api.gateio.ws"], hosts);
+        c.close();
+    }
+
+    #[test]
+    fn test_crawler_reload() {
+        let (a, a_count) = serve_status();
+        let (b, b_count) = serve_status();
+        let target = |addr: &str| TargetConfig {
+            host_addr: addr.to_string(),
+            task_name: TaskName::NetworkFunctional,
+            registry: Some(crate::config::Registry {
+                prefix: "".to_string(),
+                labels: [("node".to_string(), addr.to_string())].into(),
+            }),
+            ..Default::default()
+        };
+        let mut cfg = CrawlerConfig {
+            targets: vec![target(&a)],
+            worker_n: 2,
+            mode: CrawlMode::OnDemand,
+            cache_ttl_ms: 0,
+            ..Default::default()
+        };
+        let m = Arc::new(Metrics::new(&cfg).unwrap());
+        let mut c = Crawler::new(&cfg, m.clone(), Arc::default()).unwrap();
+        let scraper = c.scraper().unwrap();
+        let task = c.tasks.read().unwrap()[0].clone();
+
+        cfg.targets.push(target(&b));
+        m.reload(&cfg).unwrap();
+        c.reload(&cfg).unwrap();
+        assert_eq!(2, c.tasks.read().unwrap().len());
+        // the unchanged target keeps its task
+        assert!(Arc::ptr_eq(&task, &c.tasks.read().unwrap()[0]));
+
+        scraper.scrape(Duration::from_secs(5));
+        assert_eq!(1, a_count.load(Ordering::SeqCst));
+        assert_eq!(1, b_count.load(Ordering::SeqCst));
+
+        cfg.targets.remove(0);
+        m.reload(&cfg).unwrap();
+        c.reload(&cfg).unwrap();
+        scraper.scrape(Duration::from_secs(5));
+        assert_eq!(1, a_count.load(Ordering::SeqCst));
+        assert_eq!(2, b_count.load(Ordering::SeqCst));
+
+        // the crawler cannot run targets unknown to the metrics
+        cfg.targets.push(target("http://127.0.0.1:1"));
+        assert!(c.reload(&cfg).is_err());
+        assert_eq!(1, c.tasks.read().unwrap().len());
+
+        c.close();
+    }
+
+    /// Reloads the crawler with cfg after the metrics,
+    /// then returns the value of the target's series after an on-demand scrape.
+    fn reload_and_scrape(c: &Crawler, m: &Metrics, cfg: &CrawlerConfig) -> f64 {
+        m.reload(cfg).unwrap();
+        c.reload(cfg).unwrap();
+        c.scraper().unwrap().scrape(Duration::from_secs(5));
+        let families = m.gather();
+        families
+            .iter()
+            .find(|f| f.get_name() == "network_functional")
+            .unwrap()
+            .get_metric()[0]
+            .get_gauge()
+            .get_value()
+    }
+
+    #[test]
+    fn test_crawler_reload_legacy_scaling() {
+        let (addr, count) = serve_status();
+        let mut cfg = CrawlerConfig {
+            targets: vec![TargetConfig {
+                host_addr: addr,
+                task_name: TaskName::NetworkFunctional,
+                ..Default::default()
+            }],
+            worker_n: 1,
+            mode: CrawlMode::OnDemand,
+            cache_ttl_ms: 0,
+            ..Default::default()
+        };
+        let m = Arc::new(Metrics::new(&cfg).unwrap());
+        let mut c = Crawler::new(&cfg, m.clone(), Arc::default()).unwrap();
+        let task = c.tasks.read().unwrap()[0].clone();
+
+        cfg.legacy_scaling = !cfg.legacy_scaling;
+        assert_ne!(0.0, reload_and_scrape(&c, &m, &cfg));
+        assert_eq!(1, count.load(Ordering::SeqCst));
+        // the task of the replaced metric is replaced as well
+        let reloaded = c.tasks.read().unwrap()[0].clone();
+        assert!(!Arc::ptr_eq(&task, &reloaded));
+        let metric = m.get_metric(calculate_hash(&cfg.targets[0])).unwrap();
+        assert!(Arc::ptr_eq(&metric, &reloaded.metric));
+
+        c.close();
+    }
+
+    #[test]
+    fn test_crawler_reload_auto_labels() {
+        let (addr, count) = serve_status();
+        let mut cfg = CrawlerConfig {
+            targets: vec![TargetConfig {
+                host_addr: addr,
+                task_name: TaskName::NetworkFunctional,
+                ..Default::default()
+            }],
+            worker_n: 1,
+            mode: CrawlMode::OnDemand,
+            cache_ttl_ms: 0,
+            ..Default::default()
+        };
+        let m = Arc::new(Metrics::new(&cfg).unwrap());
+        let mut c = Crawler::new(&cfg, m.clone(), Arc::default()).unwrap();
+
+        cfg.auto_labels.host = false;
+        assert_ne!(0.0, reload_and_scrape(&c, &m, &cfg));
+        assert_eq!(1, count.load(Ordering::SeqCst));
+        let families = m.gather();
+        let family = families
+            .iter()
+            .find(|f| f.get_name() == "network_functional")
+            .unwrap();
+        assert!(family.get_metric()[0]
+            .get_label()
+            .iter()
+            .all(|l| l.get_name() != "host"));
+
+        c.close();
+    }
+
+    #[test]
+    fn test_shutdown_wakes_up_waiters() {
+        let shutdown = Arc::new(Shutdown::default());
+        assert!(!shutdown.wait(Duration::from_millis(1)));
+
+        let s = shutdown.clone();
+        let waiter = thread::spawn(move || s.wait(Duration::from_secs(60)));
+        sleep(Duration::from_millis(50));
+        shutdown.signal();
+        assert!(waiter.join().unwrap());
+        assert!(shutdown.is_done());
+    }
+
+    #[test]
+    fn test_task_reschedule() {
+        let now = Instant::now();
+        let task = Task::new(
+            "network_functional".to_string(),
+            Endpoints::new(
+                ["network_functional", "http://127.0.0.1:26657", ""].map(String::from),
+                vec![(
+                    "http://127.0.0.1:26657".to_string(),
+                    Arc::new(Client::default()),
+                )],
+                Default::default(),
+                0,
+                None,
+            ),
+            Arc::new(Metric::default()),
+            None,
+            crate::tasks::network_functional,
+            Duration::from_millis(100),
+            now,
+        );
+        assert!(task.is_due(now));
+
+        assert_eq!(0, task.reschedule(now));
+        assert_eq!(now + Duration::from_millis(100), task.next_run());
+        assert!(!task.is_due(now));
+
+        // falling behind several intervals schedules from now rather than catching up
+        let late = now + Duration::from_millis(350);
+        assert_eq!(2, task.reschedule(late));
+        assert_eq!(late + Duration::from_millis(100), task.next_run());
+    }
+
+    #[test]
+    fn test_task_in_flight() {
+        let task = Task::new(
+            "network_functional".to_string(),
+            Endpoints::new(
+                ["network_functional", "http://127.0.0.1:26657", ""].map(String::from),
+                vec![(
+                    "http://127.0.0.1:26657".to_string(),
+                    Arc::new(Client::default()),
+                )],
+                Default::default(),
+                0,
+                None,
+            ),
+            Arc::new(Metric::default()),
+            None,
+            crate::tasks::network_functional,
+            Duration::from_millis(100),
+            Instant::now(),
+        );
+        assert!(task.start());
+        assert!(!task.start());
+        task.finish();
+        assert!(task.start());
     }
 }
@@ -0,0 +1,372 @@
+use anyhow::{anyhow, bail, Result};
+use log::{debug, warn};
+use prometheus::IntGaugeVec;
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use crate::{
+    client::{CircuitOpen, Client},
+    config::EndpointMode,
+};
+
+/// The exporter metrics describing the endpoints of targets.
+#[derive(Clone)]
+pub(crate) struct EndpointMetrics {
+    /// the endpoint which served the latest sample labeled by task, addr, subject and endpoint.
+    pub(crate) info: IntGaugeVec,
+    /// the number of endpoints disagreeing with the others labeled by task, addr and subject.
+    pub(crate) divergence: IntGaugeVec,
+}
+
+/// The RPC endpoints serving one target.
+///
+/// A task runs against the endpoints one by one until one of them succeeds,
+/// starting from the first endpoint in the Priority mode
+/// or from the next endpoint of the previous run in the RoundRobin mode.
+///
+/// In the Quorum mode a task runs against all endpoints at once,
+/// the value returned by at least quorum endpoints wins.
+pub(crate) struct Endpoints {
+    /// the task, addr and subject labels of the target.
+    labels: [String; 3],
+    endpoints: Vec<(String, Arc<Client>)>,
+    mode: EndpointMode,
+    quorum: usize,
+    next: AtomicUsize,
+    served: Mutex<Option<String>>,
+    metrics: Option<EndpointMetrics>,
+}
+
+impl Endpoints {
+    /// Returns an Endpoints instance, the first endpoint is the primary one.
+    ///
+    /// A zero quorum means the majority of endpoints.
+    pub(crate) fn new(
+        labels: [String; 3],
+        endpoints: Vec<(String, Arc<Client>)>,
+        mode: EndpointMode,
+        quorum: usize,
+        metrics: Option<EndpointMetrics>,
+    ) -> Self {
+        assert!(
+            !endpoints.is_empty(),
+            "a target needs at least one endpoint"
+        );
+        Endpoints {
+            labels,
+            mode,
+            quorum: match quorum {
+                0 => endpoints.len() / 2 + 1,
+                n => n,
+            },
+            endpoints,
+            next: AtomicUsize::new(0),
+            served: Mutex::new(None),
+            metrics,
+        }
+    }
+
+    /// Returns the task, addr and subject labels of the target.
+    pub(crate) fn labels(&self) -> &[String; 3] {
+        &self.labels
+    }
+
+    /// Returns the primary endpoint.
+    pub(crate) fn primary(&self) -> &str {
+        &self.endpoints[0].0
+    }
+
+    /// Runs f against the endpoints until one of them succeeds.
+    ///
+    /// Returns the error of the only endpoint or an error listing every endpoint's error,
+    /// if all of them are quarantined returns the first CircuitOpen error.
+    pub(crate) fn run<N, F>(&self, f: F) -> Result<N>
+    where
+        N: Clone + Debug + PartialEq + Send,
+        F: Fn(&Client, &str) -> Result<N> + Sync,
+    {
+        let n = self.endpoints.len();
+        let start = match self.mode {
+            EndpointMode::Priority => 0,
+            EndpointMode::RoundRobin => self.next.fetch_add(1, Ordering::SeqCst) % n,
+            EndpointMode::Quorum => return self.run_quorum(f),
+        };
+
+        let mut errors = Vec::with_capacity(n);
+        for i in 0..n {
+            let (addr, client) = &self.endpoints[(start + i) % n];
+            match f(client, addr) {
+                Ok(v) => {
+                    self.serve(addr);
+                    return Ok(v);
+                }
+                Err(e) => {
+                    if i + 1 < n {
+                        debug!(
+                            "task:{}, addr:{}, endpoint:{} failed over: {}",
+                            self.labels[0],
+                            self.primary(),
+                            addr,
+                            e
+                        );
+                    }
+                    errors.push((addr, e));
+                }
+            }
+        }
+
+        if errors.len() == 1 || errors.iter().all(|(_, e)| e.is::<CircuitOpen>()) {
+            return Err(errors.swap_remove(0).1);
+        }
+
+        let errors: Vec<String> = errors
+            .iter()
+            .map(|(addr, e)| format!("endpoint:{} err:{}", addr, e))
+            .collect();
+        Err(anyhow!("all endpoints failed: [{}]", errors.join("; ")))
+    }
+
+    /// Runs f against all endpoints in parallel and returns the value agreed by a quorum.
+    ///
+    /// Every endpoint answering a different value than the agreed one is logged
+    /// and counted into the divergence gauge.
+    fn run_quorum<N, F>(&self, f: F) -> Result<N>
+    where
+        N: Clone + Debug + PartialEq + Send,
+        F: Fn(&Client, &str) -> Result<N> + Sync,
+    {
+        let results: Vec<(&str, Result<N>)> = thread::scope(|s| {
+            let handles: Vec<_> = self
+                .endpoints
+                .iter()
+                .map(|(addr, client)| (addr.as_str(), s.spawn(|| f(client, addr))))
+                .collect();
+            handles
+                .into_iter()
+                .map(|(addr, h)| {
+                    let result = h
+                        .join()
+                        .unwrap_or_else(|_| Err(anyhow!("endpoint thread panicked")));
+                    (addr, result)
+                })
+                .collect()
+        });
+
+        // the values with their votes in the order of first appearance
+        let mut votes: Vec<(N, usize)> = vec![];
+        for v in results.iter().filter_map(|(_, r)| r.as_ref().ok()) {
+            match votes.iter_mut().find(|(value, _)| value == v) {
+                Some((_, count)) => *count += 1,
+                None => votes.push((v.clone(), 1)),
+            }
+        }
+        let answered: usize = votes.iter().map(|(_, count)| count).sum();
+        let winner = votes.iter().max_by_key(|(_, count)| *count).cloned();
+        let divergence = answered - winner.as_ref().map(|(_, count)| *count).unwrap_or(0);
+        if let Some(m) = &self.metrics {
+            m.divergence
+                .with_label_values(&self.labels.each_ref().map(String::as_str))
+                .set(divergence as i64);
+        }
+
+        for (addr, result) in &results {
+            match (result, &winner) {
+                (Err(e), _) => debug!(
+                    "task:{}, addr:{}, endpoint:{} failed: {}",
+                    self.labels[0],
+                    self.primary(),
+                    addr,
+                    e
+                ),
+                (Ok(v), Some((agreed, _))) if v != agreed => warn!(
+                    "task:{}, addr:{}, endpoint:{} disagreed, got:{:?}, agreed:{:?}",
+                    self.labels[0],
+                    self.primary(),
+                    addr,
+                    v,
+                    agreed
+                ),
+                _ => {}
+            }
+        }
+
+        match winner {
+            Some((agreed, count)) if count >= self.quorum => Ok(agreed),
+            Some((_, count)) => bail!(
+                "no quorum, the most agreed value got {} of {} required votes, {} of {} endpoints answered",
+                count,
+                self.quorum,
+                answered,
+                self.endpoints.len()
+            ),
+            None => {
+                let quarantined = results
+                    .iter()
+                    .all(|(_, r)| matches!(r, Err(e) if e.is::<CircuitOpen>()));
+                match results.into_iter().find_map(|(_, r)| r.err()) {
+                    Some(e) if quarantined => Err(e),
+                    _ => bail!(
+                        "no quorum, none of {} endpoints answered",
+                        self.endpoints.len()
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Records the endpoint serving the latest sample.
+    fn serve(&self, addr: &str) {
+        let mut served = self.served.lock().expect("lock served failed");
+        if served.as_deref() == Some(addr) {
+            return;
+        }
+
+        if let Some(m) = &self.metrics {
+            let [task, primary, subject] = self.labels.each_ref().map(String::as_str);
+            if let Some(old) = served.as_deref() {
+                let _ = m.info.remove_label_values(&[task, primary, subject, old]);
+            }
+            m.info
+                .with_label_values(&[task, primary, subject, addr])
+                .set(1);
+        }
+        if served.is_some() {
+            warn!(
+                "task:{}, addr:{} is served by endpoint:{} now",
+                self.labels[0],
+                self.primary(),
+                addr
+            );
+        }
+        *served = Some(addr.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use prometheus::{core::Collector, Opts};
+
+    fn endpoint_metrics() -> EndpointMetrics {
+        EndpointMetrics {
+            info: IntGaugeVec::new(
+                Opts::new("info", "info"),
+                &["task", "addr", "subject", "endpoint"],
+            )
+            .unwrap(),
+            divergence: IntGaugeVec::new(
+                Opts::new("divergence", "divergence"),
+                &["task", "addr", "subject"],
+            )
+            .unwrap(),
+        }
+    }
+
+    fn endpoints(mode: EndpointMode, metrics: Option<EndpointMetrics>) -> Endpoints {
+        Endpoints::new(
+            ["network_functional", "a", ""].map(String::from),
+            vec![
+                ("a".to_string(), Arc::new(Client::default())),
+                ("b".to_string(), Arc::new(Client::default())),
+                ("c".to_string(), Arc::new(Client::default())),
+            ],
+            mode,
+            0,
+            metrics,
+        )
+    }
+
+    /// Returns a number identifying the endpoint.
+    fn id(addr: &str) -> u64 {
+        addr.as_bytes()[0] as u64
+    }
+
+    #[test]
+    fn test_endpoints_priority() {
+        let m = endpoint_metrics();
+        let info = m.info.clone();
+        let e = endpoints(EndpointMode::Priority, Some(m));
+
+        let got = e.run(|_, addr| Ok(id(addr))).unwrap();
+        assert_eq!(id("a"), got);
+        assert_eq!(
+            1,
+            info.with_label_values(&["network_functional", "a", "", "a"])
+                .get()
+        );
+
+        let got = e
+            .run(|_, addr| match addr {
+                "a" => bail!("a is down"),
+                _ => Ok(id(addr)),
+            })
+            .unwrap();
+        assert_eq!(id("b"), got);
+        // the previous serving endpoint is removed from the info gauge
+        assert_eq!(1, info.collect()[0].get_metric().len());
+        assert_eq!(
+            1,
+            info.with_label_values(&["network_functional", "a", "", "b"])
+                .get()
+        );
+
+        let err = e.run(|_, addr| -> Result<u64> { bail!("{} is down", addr) });
+        let err = err.unwrap_err().to_string();
+        assert!(
+            err.contains("a is down") && err.contains("b is down") && err.contains("c is down")
+        );
+    }
+
+    #[test]
+    fn test_endpoints_round_robin() {
+        let e = endpoints(EndpointMode::RoundRobin, None);
+        let got: Vec<u64> = (0..4)
+            .map(|_| e.run(|_, addr| Ok(id(addr))).unwrap())
+            .collect();
+        assert_eq!(vec![id("a"), id("b"), id("c"), id("a")], got);
+    }
+
+    #[test]
+    fn test_endpoints_quorum() {
+        let m = endpoint_metrics();
+        let divergence = m.divergence.clone();
+        let e = endpoints(EndpointMode::Quorum, Some(m));
+        let divergence = || {
+            divergence
+                .with_label_values(&["network_functional", "a", ""])
+                .get()
+        };
+
+        assert_eq!(7u64, e.run(|_, _| Ok(7u64)).unwrap());
+        assert_eq!(0, divergence());
+
+        // c is lagging behind
+        let got = e.run(|_, addr| Ok(if addr == "c" { 6u64 } else { 7u64 }));
+        assert_eq!(7, got.unwrap());
+        assert_eq!(1, divergence());
+
+        // b is down and c is lagging behind
+        let got = e.run(|_, addr| match addr {
+            "b" => bail!("b is down"),
+            "c" => Ok(6u64),
+            _ => Ok(7u64),
+        });
+        assert!(got.unwrap_err().to_string().contains("no quorum"));
+        assert_eq!(1, divergence());
+
+        // b is down but a and c still agree
+        let got = e.run(|_, addr| match addr {
+            "b" => bail!("b is down"),
+            _ => Ok(7u64),
+        });
+        assert_eq!(7, got.unwrap());
+        assert_eq!(0, divergence());
+    }
+}
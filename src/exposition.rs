@@ -0,0 +1,346 @@
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use std::collections::HashMap;
+
+use crate::utils::calculate_hash;
+
+/// The content type of the OpenMetrics text format.
+const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// The exposition formats served on the /metrics path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+    /// The classic Prometheus text format of version 0.0.4.
+    Text,
+    /// The OpenMetrics text format of version 1.0.0.
+    OpenMetrics,
+    /// The Prometheus protobuf format of length delimited MetricFamily messages.
+    Protobuf,
+}
+
+impl Format {
+    /// Returns the format the Accept header prefers the most,
+    /// falls back to Text if the header is missing or accepts none of the formats.
+    pub(crate) fn negotiate(accept: Option<&str>) -> Self {
+        let mut best = (Format::Text, 0.0);
+        for media_range in accept.unwrap_or("").split(',') {
+            let mut params = media_range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or("");
+            let params: HashMap<&str, &str> = params.filter_map(|p| p.split_once('=')).collect();
+            let format = match media_type {
+                "application/openmetrics-text" => Format::OpenMetrics,
+                "application/vnd.google.protobuf"
+                    if params.get("proto") == Some(&"io.prometheus.client.MetricFamily")
+                        && params.get("encoding") == Some(&"delimited") =>
+                {
+                    Format::Protobuf
+                }
+                "text/plain" | "text/*" | "*/*" => Format::Text,
+                _ => continue,
+            };
+            let q = params
+                .get("q")
+                .and_then(|q| q.parse::<f64>().ok())
+                .unwrap_or(1.0);
+            // the earlier one wins a tie
+            if q > best.1 {
+                best = (format, q);
+            }
+        }
+        best.0
+    }
+
+    /// Returns the value of the Content-Type header of the format.
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            Format::Text => prometheus::TEXT_FORMAT,
+            Format::OpenMetrics => OPENMETRICS_FORMAT,
+            Format::Protobuf => prometheus::PROTOBUF_FORMAT,
+        }
+    }
+}
+
+/// What the OpenMetrics format exposes besides the metric families.
+#[derive(Debug, Default)]
+pub(crate) struct Metadata {
+    /// the units keyed by metric family name.
+    pub(crate) units: HashMap<String, String>,
+    /// the unix times series were created keyed by series_key.
+    pub(crate) created: HashMap<u64, f64>,
+}
+
+/// Returns a key identifying the series of the metric family by its labels,
+/// the order of labels and the stale label of the stale policy do not matter.
+pub(crate) fn series_key(name: &str, labels: &[(&str, &str)]) -> u64 {
+    let mut labels: Vec<&(&str, &str)> = labels.iter().filter(|(n, _)| *n != "stale").collect();
+    labels.sort();
+    calculate_hash(&(name, labels))
+}
+
+/// Removes the timestamps of all samples.
+pub(crate) fn strip_timestamps(families: &mut [MetricFamily]) {
+    families
+        .iter_mut()
+        .flat_map(|f| f.mut_metric().iter_mut())
+        .for_each(|m| m.clear_timestamp_ms());
+}
+
+/// Encodes the metric families in the OpenMetrics text format.
+///
+/// Counters lose their _total suffix in the family name as the format requires,
+/// units are only declared for families whose names end with them.
+pub(crate) fn encode_openmetrics(families: &[MetricFamily], metadata: &Metadata) -> String {
+    let mut buf = String::new();
+    for mf in families {
+        let name = mf.get_name();
+        let (family, kind) = match mf.get_field_type() {
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+
+        buf.push_str(&format!("# TYPE {} {}\n", family, kind));
+        if let Some(unit) = metadata.units.get(name) {
+            if family.ends_with(&format!("_{}", unit)) {
+                buf.push_str(&format!("# UNIT {} {}\n", family, unit));
+            }
+        }
+        if !mf.get_help().is_empty() {
+            buf.push_str(&format!("# HELP {} {}\n", family, escape(mf.get_help())));
+        }
+
+        for m in mf.get_metric() {
+            let labels: Vec<(&str, &str)> = m
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name(), l.get_value()))
+                .collect();
+            let created = metadata.created.get(&series_key(name, &labels));
+            let mut sample = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                write_sample(&mut buf, family, suffix, m, extra, value)
+            };
+
+            match mf.get_field_type() {
+                MetricType::COUNTER => sample("_total", None, m.get_counter().get_value()),
+                MetricType::GAUGE => sample("", None, m.get_gauge().get_value()),
+                MetricType::UNTYPED => sample("", None, m.get_untyped().get_value()),
+                MetricType::HISTOGRAM => {
+                    let h = m.get_histogram();
+                    for b in h.get_bucket() {
+                        let le = format_float(b.get_upper_bound());
+                        sample("_bucket", Some(("le", le)), b.get_cumulative_count() as f64);
+                    }
+                    if !matches!(h.get_bucket().last(), Some(b) if b.get_upper_bound().is_infinite())
+                    {
+                        let le = format_float(f64::INFINITY);
+                        sample("_bucket", Some(("le", le)), h.get_sample_count() as f64);
+                    }
+                    sample("_count", None, h.get_sample_count() as f64);
+                    sample("_sum", None, h.get_sample_sum());
+                }
+                MetricType::SUMMARY => {
+                    let s = m.get_summary();
+                    for q in s.get_quantile() {
+                        let quantile = format_float(q.get_quantile());
+                        sample("", Some(("quantile", quantile)), q.get_value());
+                    }
+                    sample("_count", None, s.get_sample_count() as f64);
+                    sample("_sum", None, s.get_sample_sum());
+                }
+            }
+
+            if let Some(created) = created {
+                if matches!(
+                    mf.get_field_type(),
+                    MetricType::COUNTER | MetricType::HISTOGRAM | MetricType::SUMMARY
+                ) {
+                    let mut m = m.clone();
+                    m.clear_timestamp_ms();
+                    write_sample(&mut buf, family, "_created", &m, None, *created);
+                }
+            }
+        }
+    }
+    buf.push_str("# EOF\n");
+    buf
+}
+
+/// Writes a sample line of the series with an optional extra label,
+/// the timestamp of the series is written in seconds.
+fn write_sample(
+    buf: &mut String,
+    family: &str,
+    suffix: &str,
+    m: &Metric,
+    extra: Option<(&str, String)>,
+    value: f64,
+) {
+    buf.push_str(family);
+    buf.push_str(suffix);
+
+    let labels: Vec<String> = m
+        .get_label()
+        .iter()
+        .map(|l| (l.get_name(), l.get_value().to_string()))
+        .chain(extra)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(&value)))
+        .collect();
+    if !labels.is_empty() {
+        buf.push_str(&format!("{{{}}}", labels.join(",")));
+    }
+
+    buf.push(' ');
+    buf.push_str(&format_float(value));
+    if m.get_timestamp_ms() != 0 {
+        buf.push(' ');
+        buf.push_str(&format_float(m.get_timestamp_ms() as f64 / 1000.0));
+    }
+    buf.push('\n');
+}
+
+/// Returns the float in the OpenMetrics notation of infinities.
+pub(crate) fn format_float(v: f64) -> String {
+    match v {
+        f64::INFINITY => "+Inf".to_string(),
+        f64::NEG_INFINITY => "-Inf".to_string(),
+        v => v.to_string(),
+    }
+}
+
+/// Escapes the backslashes, double quotes and line feeds of a label value or help text.
+fn escape(v: &str) -> String {
+    v.replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{core::Collector, Gauge, HistogramOpts, HistogramVec, IntCounterVec, Opts};
+
+    #[test]
+    fn test_format_negotiate() {
+        assert_eq!(Format::Text, Format::negotiate(None));
+        assert_eq!(Format::Text, Format::negotiate(Some("application/json")));
+        assert_eq!(Format::Text, Format::negotiate(Some("*/*")));
+        assert_eq!(
+            Format::OpenMetrics,
+            Format::negotiate(Some(
+                "application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"
+            ))
+        );
+        assert_eq!(
+            Format::Text,
+            Format::negotiate(Some(
+                "application/openmetrics-text;q=0.3, text/plain;version=0.0.4;q=0.5"
+            ))
+        );
+        assert_eq!(
+            Format::Text,
+            Format::negotiate(Some("application/openmetrics-text;q=0"))
+        );
+        assert_eq!(
+            Format::Protobuf,
+            Format::negotiate(Some(
+                "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3,*/*;q=0.2"
+            ))
+        );
+        // only the delimited MetricFamily messages are served
+        assert_eq!(
+            Format::Text,
+            Format::negotiate(Some(
+                "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=text"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_encode_openmetrics() {
+        let gauge = Gauge::with_opts(
+            Opts::new("bridge_reserve_usdt", "the \"USDT\" reserve\non BSC")
+                .const_label("host", "http://127.0.0.1:8545"),
+        )
+        .unwrap();
+        gauge.set(0.5);
+        let counter = IntCounterVec::new(
+            Opts::new("task_successes_total", "the number of successes"),
+            &["task"],
+        )
+        .unwrap();
+        counter.with_label_values(&["get_price"]).inc_by(3);
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("task_duration_seconds", "the duration").buckets(vec![0.5, 1.0]),
+            &["task"],
+        )
+        .unwrap();
+        histogram.with_label_values(&["get_price"]).observe(0.7);
+
+        let mut families = vec![];
+        families.extend(gauge.collect());
+        families.extend(counter.collect());
+        families.extend(histogram.collect());
+        families[0].mut_metric()[0].set_timestamp_ms(1700000000123);
+
+        let metadata = Metadata {
+            units: [
+                ("bridge_reserve_usdt", "usdt"),
+                ("task_duration_seconds", "seconds"),
+                // the name does not end with the unit
+                ("task_successes_total", "seconds"),
+            ]
+            .into_iter()
+            .map(|(n, u)| (n.to_string(), u.to_string()))
+            .collect(),
+            created: [
+                (
+                    series_key("task_successes_total", &[("task", "get_price")]),
+                    1700000000.5,
+                ),
+                (
+                    series_key("task_duration_seconds", &[("task", "get_price")]),
+                    1700000001.0,
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        let want = r#"# TYPE bridge_reserve_usdt gauge
+# UNIT bridge_reserve_usdt usdt
+# HELP bridge_reserve_usdt the \"USDT\" reserve\non BSC
+bridge_reserve_usdt{host="http://127.0.0.1:8545"} 0.5 1700000000.123
+# TYPE task_successes counter
+# HELP task_successes the number of successes
+task_successes_total{task="get_price"} 3
+task_successes_created{task="get_price"} 1700000000.5
+# TYPE task_duration_seconds histogram
+# UNIT task_duration_seconds seconds
+# HELP task_duration_seconds the duration
+task_duration_seconds_bucket{task="get_price",le="0.5"} 0
+task_duration_seconds_bucket{task="get_price",le="1"} 1
+task_duration_seconds_bucket{task="get_price",le="+Inf"} 1
+task_duration_seconds_count{task="get_price"} 1
+task_duration_seconds_sum{task="get_price"} 0.7
+task_duration_seconds_created{task="get_price"} 1700000001
+# EOF
+"#;
+        assert_eq!(want, encode_openmetrics(&families, &metadata));
+
+        strip_timestamps(&mut families);
+        assert_eq!(0, families[0].get_metric()[0].get_timestamp_ms());
+    }
+
+    #[test]
+    fn test_series_key() {
+        assert_eq!(
+            series_key("a", &[("x", "1"), ("y", "2")]),
+            series_key("a", &[("y", "2"), ("stale", "true"), ("x", "1")])
+        );
+        assert_ne!(
+            series_key("a", &[("x", "1")]),
+            series_key("b", &[("x", "1")])
+        );
+    }
+}
@@ -1,11 +1,21 @@
 use std::{env, path::Path, sync::Arc};
 
-use prometheus::core::AtomicU64;
+use log::{error, info};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
 
+mod client;
 mod config;
 mod crawler;
+mod endpoints;
+mod exposition;
 mod metrics;
+mod pushgateway;
+mod remote_write;
 mod server;
+mod sinks;
 mod tasks;
 mod utils;
 
@@ -19,28 +29,61 @@ fn main() {
 fn run(cfg_path: &str) {
     let cfg = config::read_config(Path::new(cfg_path)).expect("read config failed");
 
-    let log_level = match cfg.log_level.to_lowercase().as_ref() {
-        "trace" => log::Level::Trace,
-        "debug" => log::Level::Debug,
-        "error" => log::Level::Error,
-        "warn" => log::Level::Warn,
-        "info" => log::Level::Info,
-        _ => log::Level::Trace,
-    };
-    simple_logger::init_with_level(log_level).expect("simple logger init failed");
+    // the logger lets everything through and the max level filters,
+    // which is the only level a reload can change
+    simple_logger::init_with_level(log::Level::Trace).expect("simple logger init failed");
+    log::set_max_level(parse_log_level(&cfg.log_level).to_level_filter());
 
-    let metrics =
-        Arc::new(metrics::Metrics::<AtomicU64>::new(&cfg.crawler).expect("metrics new failed"));
-    let server = server::Server::new(&cfg.server, metrics.clone());
-    let mut crawler = crawler::Crawler::new(&cfg.crawler, metrics).expect("crawler new failed");
+    let metrics = Arc::new(metrics::Metrics::new(&cfg.crawler).expect("metrics new failed"));
+    let sinks = Arc::new(sinks::Sinks::new(&cfg.sinks).expect("sinks new failed"));
+    let mut crawler =
+        crawler::Crawler::new(&cfg.crawler, metrics.clone(), sinks).expect("crawler new failed");
+    let server = cfg
+        .server
+        .enabled
+        .then(|| server::Server::new(&cfg.server, metrics.clone(), crawler.scraper()));
+    let pusher = cfg.pushgateway.as_ref().map(|p| {
+        pushgateway::Pusher::new(p, metrics.clone(), crawler.scraper()).expect("pusher new failed")
+    });
+    let writer = cfg.remote_write.as_ref().map(|w| {
+        remote_write::Writer::new(w, metrics.clone(), crawler.scraper())
+            .expect("remote_write writer new failed")
+    });
 
-    let threads = vec![server.run().expect("server thread run failed")];
+    let mut threads = vec![];
+    if let Some(server) = &server {
+        threads.push(server.run().expect("server thread run failed"));
+    }
+    if let Some(pusher) = &pusher {
+        threads.push(pusher.run().expect("pusher thread run failed"));
+    }
+    if let Some(writer) = &writer {
+        threads.push(writer.run().expect("remote_write thread run failed"));
+    }
+
+    let mut signals =
+        Signals::new([SIGHUP, SIGINT, SIGTERM]).expect("setting signal handler failed");
+    for signal in signals.forever() {
+        if signal != SIGHUP {
+            break;
+        }
+
+        match reload(cfg_path, &metrics, &crawler) {
+            Ok(()) => info!("config reloaded from {}", cfg_path),
+            Err(e) => error!("reload config failed, keeping the previous one: {:?}", e),
+        }
+    }
 
-    ctrlc::set_handler(move || {
+    if let Some(server) = &server {
         server.close();
-        crawler.close();
-    })
-    .expect("setting Ctrl-C handler failed");
+    }
+    if let Some(pusher) = &pusher {
+        pusher.close();
+    }
+    if let Some(writer) = &writer {
+        writer.close();
+    }
+    crawler.close();
 
     for t in threads {
         // no matter what we need to wait all of the thread stopped
@@ -48,6 +91,34 @@ fn run(cfg_path: &str) {
     }
 }
 
+/// Reloads the log level and targets from the config file,
+/// the outcome is recorded in the config reload success metric.
+fn reload(
+    cfg_path: &str,
+    metrics: &metrics::Metrics,
+    crawler: &crawler::Crawler,
+) -> anyhow::Result<()> {
+    let result = config::read_config(Path::new(cfg_path)).and_then(|cfg| {
+        metrics.reload(&cfg.crawler)?;
+        crawler.reload(&cfg.crawler)?;
+        log::set_max_level(parse_log_level(&cfg.log_level).to_level_filter());
+        Ok(())
+    });
+    metrics.config_reloaded(result.is_ok());
+    result
+}
+
+fn parse_log_level(level: &str) -> log::Level {
+    match level.to_lowercase().as_ref() {
+        "trace" => log::Level::Trace,
+        "debug" => log::Level::Debug,
+        "error" => log::Level::Error,
+        "warn" => log::Level::Warn,
+        "info" => log::Level::Info,
+        _ => log::Level::Trace,
+    }
+}
+
 fn print_help() {
     println!(
         "Usage findora-exporter [OPTION]... [FILE]...
@@ -82,7 +153,26 @@ mod test_util {
     use std::{
         fs,
         path::{Path, PathBuf},
+        thread,
     };
+    use tiny_http::{Request, ResponseBox, Server};
+
+    /// Serves every request with the response of the handler on a local port,
+    /// returns the served address such as http://127.0.0.1:12345.
+    pub(crate) fn serve<F>(mut handler: F) -> String
+    where
+        F: FnMut(&mut Request) -> ResponseBox + Send + 'static,
+    {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = format!("http://{}", server.server_addr());
+        thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let response = handler(&mut request);
+                let _ = request.respond(response);
+            }
+        });
+        addr
+    }
 
     pub(crate) struct TmpDir {
         path: Option<PathBuf>,
@@ -150,8 +240,7 @@ mod tests {
         cfg.crawler.targets = vec![config::Target {
             host_addr: "https://prod-mainnet.prod.findora.org:26657".to_string(),
             task_name: config::TaskName::TotalCountOfValidators,
-            registry: None,
-            extra_opts: None,
+            ..Default::default()
         }];
         let json = serde_json::to_string(&cfg).unwrap();
         fs::write(&cfg_path, &json).unwrap();
@@ -1,152 +1,1953 @@
 use anyhow::{bail, Context, Result};
+use log::warn;
 use prometheus::{
-    core::{Atomic, GenericGauge},
-    proto::MetricFamily,
-    Registry,
+    core::{Collector, Desc, MetricVec, MetricVecBuilder},
+    proto::{LabelPair, MetricFamily},
+    Counter, CounterVec, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
 };
 
-use crate::{config::TaskName, utils::calculate_hash};
+use crate::{
+    config::{AutoLabels, StalePolicy, TaskName},
+    endpoints::EndpointMetrics,
+    exposition::{series_key, Metadata},
+    sinks::Point,
+    tasks::{definition, Kind, Sample},
+    utils::{calculate_hash, host_of, is_valid_metric_name},
+};
+
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
 
-use std::{collections::HashMap, sync::Arc};
+/// The label names of the exporter metrics describing the runs of targets.
+const TASK_LABELS: &[&str] = &["task", "addr", "subject"];
+
+/// Returns the values of TASK_LABELS identifying the target.
+pub(crate) fn task_labels(target: &crate::config::Target) -> [String; 3] {
+    [
+        target.task_name.name().to_string(),
+        target.host_addr.clone(),
+        target
+            .extra_opts
+            .as_ref()
+            .map(|o| o.subject())
+            .unwrap_or_default(),
+    ]
+}
 
 /// A wrapping collection for Metric structure.
-pub(crate) struct Metrics<T: Atomic> {
-    metrics: HashMap<u64, Arc<Metric<T>>>,
+pub(crate) struct Metrics {
+    metrics: RwLock<HashMap<u64, Arc<Metric>>>,
+    /// the registry shared by the metrics of all targets,
+    /// targets of the same metric name are the series of a single family.
+    targets: RwLock<Registry>,
+    /// the registry of metrics describing the exporter itself.
+    internal: Registry,
+    task_stats: TaskStatsVec,
+    config_reload_success: IntGauge,
+    skipped_ticks: IntCounterVec,
+    /// the unix time each series of skipped_ticks was created.
+    skipped_ticks_created: Mutex<HashMap<[String; 4], f64>>,
+    circuit_breaker_states: IntGaugeVec,
+    endpoints: EndpointMetrics,
 }
 
-impl<T> Metrics<T>
-where
-    T: Atomic + 'static,
-{
+impl Metrics {
     /// Returns a Metrics instance.
     ///
     /// This method registers Metric structures for managing easily.
     /// Returns error when registering Metric on failure.
     pub(crate) fn new(cfg: &crate::config::Crawler) -> Result<Self> {
-        let mut metrics = HashMap::with_capacity(cfg.targets.len());
+        let internal = Registry::new();
+        let task_stats = TaskStatsVec::new(&internal)?;
+
+        let config_reload_success = IntGauge::new(
+            "findora_exporter_config_reload_success",
+            "whether the latest reload of the config file succeeded, 1 for success and 0 for failure",
+        )
+        .context("new findora_exporter_config_reload_success failed")?;
+        internal
+            .register(Box::new(config_reload_success.clone()))
+            .context("register config_reload_success failed")?;
+        config_reload_success.set(1);
+
+        let skipped_ticks = IntCounterVec::new(
+            Opts::new(
+                "findora_exporter_skipped_ticks_total",
+                "the number of scheduled runs of a target that were not enqueued",
+            ),
+            &["task", "addr", "subject", "reason"],
+        )
+        .context("new findora_exporter_skipped_ticks_total failed")?;
+        internal
+            .register(Box::new(skipped_ticks.clone()))
+            .context("register skipped_ticks failed")?;
+
+        let circuit_breaker_states = IntGaugeVec::new(
+            Opts::new(
+                "findora_exporter_circuit_breaker_state",
+                "the circuit breaker state of an upstream host, 0 closed, 1 open and 2 half-open",
+            ),
+            &["host"],
+        )
+        .context("new findora_exporter_circuit_breaker_state failed")?;
+        internal
+            .register(Box::new(circuit_breaker_states.clone()))
+            .context("register circuit_breaker_states failed")?;
+
+        let endpoint_info = IntGaugeVec::new(
+            Opts::new(
+                "findora_exporter_endpoint_info",
+                "the endpoint which served the latest sample of a target",
+            ),
+            &["task", "addr", "subject", "endpoint"],
+        )
+        .context("new findora_exporter_endpoint_info failed")?;
+        internal
+            .register(Box::new(endpoint_info.clone()))
+            .context("register endpoint_info failed")?;
+
+        let endpoint_divergence = IntGaugeVec::new(
+            Opts::new(
+                "findora_exporter_endpoint_divergence",
+                "the number of endpoints of a quorum target answering a different value than the agreed one",
+            ),
+            TASK_LABELS,
+        )
+        .context("new findora_exporter_endpoint_divergence failed")?;
+        internal
+            .register(Box::new(endpoint_divergence.clone()))
+            .context("register endpoint_divergence failed")?;
+
+        let metrics = Metrics {
+            metrics: RwLock::new(HashMap::with_capacity(cfg.targets.len())),
+            targets: RwLock::new(Registry::new()),
+            internal,
+            task_stats,
+            config_reload_success,
+            skipped_ticks,
+            skipped_ticks_created: Mutex::new(HashMap::new()),
+            circuit_breaker_states,
+            endpoints: EndpointMetrics {
+                info: endpoint_info,
+                divergence: endpoint_divergence,
+            },
+        };
+        metrics.reload(cfg)?;
+        Ok(metrics)
+    }
+
+    /// Replaces the Metric structures with the ones of the targets of cfg.
+    ///
+    /// Unchanged targets keep their Metric structures and values,
+    /// Metric structures of removed or changed targets are dropped
+    /// along with the exporter series describing their runs and endpoints.
+    ///
+    /// The series are registered into a new registry replacing the shared one,
+    /// as a registry never forgets the label names of a metric name once registered,
+    /// which would keep a reload from changing them.
+    ///
+    /// Targets of the same metric name need the same label names and distinct label values,
+    /// targets need distinct task labels for the exporter series describing their runs,
+    /// nothing changes when a target conflicts with another one or fails to register.
+    pub(crate) fn reload(&self, cfg: &crate::config::Crawler) -> Result<()> {
+        let mut hashes = HashSet::with_capacity(cfg.targets.len());
+        let mut labels = HashSet::with_capacity(cfg.targets.len());
         for target in &cfg.targets {
-            metrics.insert(
-                calculate_hash(target),
-                Arc::new(
-                    Metric::new(target)
-                        .with_context(|| format!("new metric failed: {:?}", target))?,
-                ),
-            );
+            if !hashes.insert(calculate_hash(target)) {
+                bail!("duplicate target: {:?}", target);
+            }
+            if !labels.insert(task_labels(target)) {
+                bail!(
+                    "a target of the same task, addr and subject labels as another one: {:?}",
+                    target
+                );
+            }
         }
 
-        Ok(Metrics { metrics })
+        let registry = Registry::new();
+        let mut created = HashMap::new();
+        {
+            let metrics = self.metrics.read().expect("read metrics failed");
+            for target in &cfg.targets {
+                let hash = calculate_hash(target);
+                let fingerprint =
+                    calculate_hash(&(target.fingerprint(), cfg.legacy_scaling, &cfg.auto_labels));
+                let metric = match metrics.get(&hash) {
+                    Some(m) if m.fingerprint == fingerprint => m.as_ref(),
+                    _ => {
+                        let mut metric = Metric::new(target, &cfg.auto_labels)
+                            .with_context(|| format!("new metric failed: {:?}", target))?;
+                        metric.fingerprint = fingerprint;
+                        metric.legacy_scaling = cfg.legacy_scaling;
+                        &created.entry(hash).or_insert((target, metric)).1
+                    }
+                };
+                register(&registry, metric).with_context(|| {
+                    format!(
+                        "register metric failed, a target of the same metric name needs the same label names and different label values: {:?}",
+                        target
+                    )
+                })?;
+            }
+        }
+
+        let mut metrics = self.metrics.write().expect("write metrics failed");
+        let removed: Vec<u64> = metrics
+            .keys()
+            .filter(|hash| !hashes.contains(hash) || created.contains_key(hash))
+            .copied()
+            .collect();
+        for hash in removed {
+            if let Some(stats) = metrics
+                .remove(&hash)
+                .as_ref()
+                .and_then(|m| m.stats.as_ref())
+            {
+                self.remove_target_series(stats);
+            }
+        }
+        for (hash, (target, mut metric)) in created {
+            metric.stats = Some(self.task_stats.with_target(target));
+            metrics.insert(hash, Arc::new(metric));
+        }
+        *self.targets.write().expect("write targets failed") = registry;
+        Ok(())
+    }
+
+    /// Removes the exporter series of the target the TaskStats describes.
+    fn remove_target_series(&self, stats: &TaskStats) {
+        self.task_stats.remove(stats);
+
+        let labels: Vec<(&str, &str)> = TASK_LABELS
+            .iter()
+            .copied()
+            .zip(stats.labels.iter().map(String::as_str))
+            .collect();
+        remove_matching(&self.skipped_ticks, &labels);
+        remove_matching(&self.endpoints.info, &labels);
+        remove_matching(&self.endpoints.divergence, &labels);
+        self.skipped_ticks_created
+            .lock()
+            .expect("lock skipped_ticks_created failed")
+            .retain(|values, _| values[..3] != stats.labels[..]);
+    }
+
+    /// Records whether the latest reload of the config file succeeded.
+    pub(crate) fn config_reloaded(&self, success: bool) {
+        self.config_reload_success.set(success as i64)
     }
 
     /// Returns a flattened vector of all metrics inside.
     pub(crate) fn gather(&self) -> Vec<MetricFamily> {
-        self.metrics
-            .values()
-            .flat_map(|metric| metric.gather())
+        self.targets
+            .read()
+            .expect("read targets failed")
+            .gather()
+            .into_iter()
+            .chain(self.internal.gather())
             .collect()
     }
 
+    /// Returns the units and the creation times of the gathered series,
+    /// which only the OpenMetrics format exposes.
+    pub(crate) fn metadata(&self) -> Metadata {
+        let mut units: HashMap<String, String> = [
+            ("findora_exporter_task_duration_seconds", "seconds"),
+            (
+                "findora_exporter_task_last_success_timestamp_seconds",
+                "seconds",
+            ),
+        ]
+        .into_iter()
+        .map(|(name, unit)| (name.to_string(), unit.to_string()))
+        .collect();
+        let mut created = HashMap::new();
+
+        for metric in self.metrics.read().expect("read metrics failed").values() {
+            if let Some(unit) = &metric.unit {
+                let desc = &metric.series.collector.desc()[0];
+                units.insert(desc.fq_name.clone(), unit.clone());
+            }
+            metric.created_times(&mut created);
+        }
+
+        let skipped_ticks_created = self
+            .skipped_ticks_created
+            .lock()
+            .expect("lock skipped_ticks_created failed");
+        for (values, t) in skipped_ticks_created.iter() {
+            let labels: Vec<(&str, &str)> = ["task", "addr", "subject", "reason"]
+                .into_iter()
+                .zip(values.iter().map(String::as_str))
+                .collect();
+            created.insert(
+                series_key("findora_exporter_skipped_ticks_total", &labels),
+                *t,
+            );
+        }
+
+        Metadata { units, created }
+    }
+
+    /// Counts n scheduled runs of the target of the task labels which were skipped for the reason.
+    pub(crate) fn skip_ticks(&self, labels: &[String; 3], reason: &str, n: u64) {
+        let [task, addr, subject] = labels.each_ref().map(String::as_str);
+        self.skipped_ticks_created
+            .lock()
+            .expect("lock skipped_ticks_created failed")
+            .entry([task, addr, subject, reason].map(String::from))
+            .or_insert_with(unix_now);
+        self.skipped_ticks
+            .with_label_values(&[task, addr, subject, reason])
+            .inc_by(n)
+    }
+
+    /// Returns the gauge vector of circuit breaker states labeled by host.
+    pub(crate) fn circuit_breaker_states(&self) -> IntGaugeVec {
+        self.circuit_breaker_states.clone()
+    }
+
+    /// Returns the metrics of endpoints.
+    pub(crate) fn endpoint_metrics(&self) -> EndpointMetrics {
+        self.endpoints.clone()
+    }
+
+    /// Returns the current values of every target ordered by task and labels.
+    pub(crate) fn values(&self) -> Vec<Values> {
+        let mut values: Vec<Values> = self
+            .metrics
+            .read()
+            .expect("read metrics failed")
+            .values()
+            .map(|m| m.values())
+            .collect();
+        values.sort_by(|a, b| (a.task, &a.labels).cmp(&(b.task, &b.labels)));
+        values
+    }
+
     /// Returns an instance of Metric with Arc wrapping.
-    pub(crate) fn get_metric(&self, hash: u64) -> Result<Arc<Metric<T>>> {
-        match self.metrics.get(&hash) {
+    pub(crate) fn get_metric(&self, hash: u64) -> Result<Arc<Metric>> {
+        match self.metrics.read().expect("read metrics failed").get(&hash) {
             Some(metric) => Ok(metric.clone()),
             None => bail!("get_metric not found: {}", hash),
         }
     }
 }
 
+/// The exporter metrics describing the runs of targets.
+struct TaskStatsVec {
+    durations: HistogramVec,
+    successes: IntCounterVec,
+    failures: IntCounterVec,
+    last_successes: IntGaugeVec,
+    ups: IntGaugeVec,
+}
+
+impl TaskStatsVec {
+    /// Returns a TaskStatsVec instance registered in the registry.
+    fn new(registry: &Registry) -> Result<Self> {
+        let durations = HistogramVec::new(
+            HistogramOpts::new(
+                "findora_exporter_task_duration_seconds",
+                "the duration of running a target including retries and failovers",
+            )
+            .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]),
+            TASK_LABELS,
+        )
+        .context("new findora_exporter_task_duration_seconds failed")?;
+        let successes = IntCounterVec::new(
+            Opts::new(
+                "findora_exporter_task_successes_total",
+                "the number of successful runs of a target",
+            ),
+            TASK_LABELS,
+        )
+        .context("new findora_exporter_task_successes_total failed")?;
+        let failures = IntCounterVec::new(
+            Opts::new(
+                "findora_exporter_task_failures_total",
+                "the number of failed runs of a target",
+            ),
+            TASK_LABELS,
+        )
+        .context("new findora_exporter_task_failures_total failed")?;
+        let last_successes = IntGaugeVec::new(
+            Opts::new(
+                "findora_exporter_task_last_success_timestamp_seconds",
+                "the unix timestamp of the latest successful run of a target",
+            ),
+            TASK_LABELS,
+        )
+        .context("new findora_exporter_task_last_success_timestamp_seconds failed")?;
+        let ups = IntGaugeVec::new(
+            Opts::new(
+                "findora_exporter_task_up",
+                "whether the latest run of a target succeeded, 1 for success and 0 for failure",
+            ),
+            TASK_LABELS,
+        )
+        .context("new findora_exporter_task_up failed")?;
+
+        registry
+            .register(Box::new(durations.clone()))
+            .context("register task durations failed")?;
+        registry
+            .register(Box::new(successes.clone()))
+            .context("register task successes failed")?;
+        registry
+            .register(Box::new(failures.clone()))
+            .context("register task failures failed")?;
+        registry
+            .register(Box::new(last_successes.clone()))
+            .context("register task last successes failed")?;
+        registry
+            .register(Box::new(ups.clone()))
+            .context("register task ups failed")?;
+
+        Ok(TaskStatsVec {
+            durations,
+            successes,
+            failures,
+            last_successes,
+            ups,
+        })
+    }
+
+    /// Returns the TaskStats of the target.
+    fn with_target(&self, target: &crate::config::Target) -> TaskStats {
+        let labels = task_labels(target);
+        let labels = labels.each_ref().map(String::as_str);
+        TaskStats {
+            duration: self.durations.with_label_values(&labels),
+            successes: self.successes.with_label_values(&labels),
+            failures: self.failures.with_label_values(&labels),
+            last_success: self.last_successes.with_label_values(&labels),
+            up: self.ups.with_label_values(&labels),
+            labels: labels.map(String::from),
+            created: unix_now(),
+        }
+    }
+
+    /// Removes the series of the TaskStats.
+    fn remove(&self, stats: &TaskStats) {
+        let labels: Vec<&str> = stats.labels.iter().map(String::as_str).collect();
+        let _ = self.durations.remove_label_values(&labels);
+        let _ = self.successes.remove_label_values(&labels);
+        let _ = self.failures.remove_label_values(&labels);
+        let _ = self.last_successes.remove_label_values(&labels);
+        let _ = self.ups.remove_label_values(&labels);
+    }
+}
+
+/// Removes the series of the vector which carry all the labels.
+fn remove_matching<T: MetricVecBuilder>(vec: &MetricVec<T>, labels: &[(&str, &str)]) {
+    let names = vec.desc()[0].variable_labels.clone();
+    for family in vec.collect() {
+        for m in family.get_metric() {
+            let values: HashMap<&str, &str> = m
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name(), l.get_value()))
+                .collect();
+            if labels.iter().all(|(n, v)| values.get(n) == Some(v)) {
+                let values: Vec<&str> = names
+                    .iter()
+                    .map(|n| values.get(n.as_str()).copied().unwrap_or_default())
+                    .collect();
+                let _ = vec.remove_label_values(&values);
+            }
+        }
+    }
+}
+
+/// The exporter metrics describing the runs of a target.
+struct TaskStats {
+    labels: [String; 3],
+    /// the unix time the series were created.
+    created: f64,
+    duration: Histogram,
+    successes: IntCounter,
+    failures: IntCounter,
+    last_success: IntGauge,
+    up: IntGauge,
+}
+
 /// A wrapping structure for Prometheus library
-pub(crate) struct Metric<T: Atomic> {
-    registry: Registry,
-    metric: GenericGauge<T>,
+pub(crate) struct Metric {
+    series: Series<Instrument>,
+    /// the extra metrics declared by the task.
+    extras: Vec<ExtraSeries>,
+    stats: Option<TaskStats>,
+    /// identifies the whole configuration of the target.
+    fingerprint: u64,
+    task_name: TaskName,
+    /// scales values into integers like the versions before real units did.
+    legacy_scaling: bool,
+    /// the unit of the task's metric.
+    unit: Option<String>,
+    /// the unix time the task's metric was created.
+    created: f64,
+    status: Mutex<Status>,
 }
 
-impl<T> Default for Metric<T>
-where
-    T: Atomic,
-{
+/// The latest runs of a target.
+#[derive(Debug, Default)]
+struct Status {
+    /// the samples of the latest successful run in real units.
+    samples: Vec<Sample>,
+    /// the unix time of the latest successful run.
+    last_success: Option<f64>,
+    /// the unix time and the error of the latest failed run.
+    last_error: Option<(f64, String)>,
+}
+
+/// The current values of a target served as JSON.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct Values {
+    pub(crate) task: &'static str,
+    /// the labels of the task's metric.
+    pub(crate) labels: BTreeMap<String, String>,
+    /// the value of the task's metric in real units, None until the first successful run.
+    pub(crate) value: Option<f64>,
+    pub(crate) extras: Vec<ExtraValue>,
+    pub(crate) last_success: Option<f64>,
+    pub(crate) last_error: Option<String>,
+    pub(crate) last_error_time: Option<f64>,
+}
+
+/// A value of an extra metric declared by the task of a target.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct ExtraValue {
+    pub(crate) metric: &'static str,
+    /// the labels of the extra metric besides the labels of the task's metric.
+    pub(crate) labels: BTreeMap<String, String>,
+    pub(crate) value: f64,
+}
+
+impl Default for Metric {
     fn default() -> Self {
+        let def = definition(TaskName::NetworkFunctional);
         Metric {
-            registry: Registry::new(),
-            metric: GenericGauge::new(
-                "network_functional",
-                "subtraction of seconds of the latest block time with the current time",
-            )
-            .unwrap(),
+            series: Series {
+                collector: Instrument::new(def.kind, Opts::new(def.name, def.help)).unwrap(),
+                max_age: None,
+                stale_policy: StalePolicy::default(),
+                updated: Arc::new(Mutex::new(None)),
+            },
+            extras: vec![],
+            stats: None,
+            fingerprint: 0,
+            task_name: TaskName::NetworkFunctional,
+            legacy_scaling: false,
+            unit: None,
+            created: unix_now(),
+            status: Mutex::new(Status::default()),
         }
     }
 }
 
-impl<T> Metric<T>
-where
-    T: Atomic + 'static,
-{
+impl Metric {
     /// Returns a Metric instance.
     ///
-    /// Registers a custom registry if not None in the config file,
-    /// if None then registers a default registry instead.
-    fn new(cfg: &crate::config::Target) -> Result<Self> {
-        let registry = match &cfg.registry {
-            Some(r) => Registry::new_custom(Some(r.prefix.clone()), Some(r.labels.clone()))
-                .with_context(|| format!("new custom registry failed: {:?}", r))?,
-            None => Registry::new(),
-        };
-
-        let metric = match cfg.task_name {
-            TaskName::ConsensusPower => GenericGauge::new(
-                "consensus_power",
-                "percentage of the current consensus network voting power",
-            )
-            .context("new consensus_power failed")?,
-            TaskName::NetworkFunctional => GenericGauge::new(
-                "network_functional",
-                "subtraction of seconds of the latest block time with the current time",
-            )
-            .context("new network_functional failed")?,
-            TaskName::TotalCountOfValidators => GenericGauge::new(
-                "total_count_of_validators",
-                "the total number of validators from the consensus network",
-            )
-            .context("new total_count_of_validators failed")?,
-            TaskName::TotalBalanceOfRelayers => GenericGauge::new(
-                "total_balance_of_relayers",
-                "the total balance of relayers from the specific bridge",
-            )
-            .context("new total_balance_of_relayers failed")?,
-            TaskName::BridgedBalance => GenericGauge::new(
-                "bridged_balance",
-                "the token balance of reserving safe on source chain",
-            )
-            .context("new bridged_balance failed")?,
-            TaskName::BridgedSupply => GenericGauge::new(
-                "bridged_supply",
-                "the token supply total minted on the destination chain",
+    /// The metric names are prefixed by the prefix of the registry in the config file,
+    /// the labels of the series are the enabled auto labels of the target
+    /// overridden by the labels of the registry.
+    fn new(cfg: &crate::config::Target, auto_labels: &AutoLabels) -> Result<Self> {
+        let def = definition(cfg.task_name);
+
+        let mut labels: HashMap<String, String> = [
+            ("host", host_of(def.upstream(&cfg.host_addr))),
+            ("task", cfg.task_name.name()),
+        ]
+        .into_iter()
+        .chain(cfg.extra_opts.iter().flat_map(|o| o.labels()))
+        .filter(|(label, _)| auto_labels.is_enabled(label))
+        .map(|(label, value)| (label.to_string(), value.to_string()))
+        .collect();
+        let prefix = match &cfg.registry {
+            Some(r) if !r.prefix.is_empty() => format!("{}_", r.prefix),
+            _ => "".to_string(),
+        };
+        if let Some(r) = &cfg.registry {
+            labels.extend(r.labels.clone());
+        }
+        if labels.contains_key("stale") {
+            bail!("the stale label is reserved for the stale policy");
+        }
+
+        let updated = Arc::new(Mutex::new(None));
+        let mut name = cfg.metric_name.as_deref().unwrap_or(def.name).to_string();
+        if !is_valid_metric_name(&name) {
+            bail!("invalid metric_name:{:?} of target:{}", name, cfg.host_addr);
+        }
+        if let Some(unit) = &cfg.unit {
+            if unit.is_empty() || !unit.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                bail!("invalid unit:{:?} of target:{}", unit, cfg.host_addr);
+            }
+            if !name.ends_with(&format!("_{}", unit)) {
+                name = format!("{}_{}", name, unit);
+            }
+        }
+        let help = cfg.help.as_deref().unwrap_or(def.help);
+        if help.trim().is_empty() {
+            bail!("empty help of target:{}", cfg.host_addr);
+        }
+
+        let name = format!("{}{}", prefix, name);
+        let instrument = Instrument::new(
+            def.kind,
+            Opts::new(&name, help).const_labels(labels.clone()),
+        )
+        .with_context(|| format!("new {} failed", name))?;
+
+        let mut extras = Vec::with_capacity(def.extras.len());
+        for extra in def.extras {
+            let name = format!("{}{}", prefix, extra.name);
+            let vec = InstrumentVec::new(
+                extra.kind,
+                Opts::new(&name, extra.help).const_labels(labels.clone()),
+                extra.labels,
             )
-            .context("new bridged_supply failed")?,
-            TaskName::NativeBalance => GenericGauge::new(
-                "native_balance",
-                "the native balance of reserving safe on source chain",
+            .with_context(|| format!("new {} failed", name))?;
+            extras.push(ExtraSeries {
+                name: extra.name,
+                series: Series::new(vec, cfg, &updated),
+                labels: Mutex::new(HashMap::new()),
+            });
+        }
+
+        Ok(Metric {
+            series: Series::new(instrument, cfg, &updated),
+            extras,
+            stats: None,
+            fingerprint: 0,
+            task_name: cfg.task_name,
+            legacy_scaling: false,
+            unit: cfg.unit.clone(),
+            created: unix_now(),
+            status: Mutex::new(Status::default()),
+        })
+    }
+
+    /// Inserts the creation times of the series of the metric keyed by series_key.
+    fn created_times(&self, created: &mut HashMap<u64, f64>) {
+        let desc = &self.series.collector.desc()[0];
+        created.insert(series_key(&desc.fq_name, &label_pairs(desc)), self.created);
+
+        for extra in &self.extras {
+            let desc = &extra.series.collector.desc()[0];
+            for (values, t) in extra.labels.lock().expect("lock labels failed").iter() {
+                let mut labels = label_pairs(desc);
+                labels.extend(
+                    desc.variable_labels
+                        .iter()
+                        .map(String::as_str)
+                        .zip(values.iter().map(String::as_str)),
+                );
+                created.insert(series_key(&desc.fq_name, &labels), *t);
+            }
+        }
+
+        if let Some(stats) = &self.stats {
+            let labels: Vec<(&str, &str)> = TASK_LABELS
+                .iter()
+                .copied()
+                .zip(stats.labels.iter().map(String::as_str))
+                .collect();
+            for name in [
+                "findora_exporter_task_duration_seconds",
+                "findora_exporter_task_successes_total",
+                "findora_exporter_task_failures_total",
+            ] {
+                created.insert(series_key(name, &labels), stats.created);
+            }
+        }
+    }
+
+    /// Returns the collectors of all series of the metric.
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        std::iter::once(Box::new(self.series.clone()) as Box<dyn Collector>)
+            .chain(
+                self.extras
+                    .iter()
+                    .map(|e| Box::new(e.series.clone()) as Box<dyn Collector>),
             )
-            .context("new native_balance failed")?,
-            TaskName::GetPrice => GenericGauge::new(
-                "get_price",
-                "the close price of the related currency pair from gate.io",
+            .collect()
+    }
+
+    /// Returns the points of the samples for the output sinks,
+    /// named and labeled like the series the samples update.
+    ///
+    /// Samples of undeclared metrics or with mismatched labels are left out.
+    pub(crate) fn points(&self, samples: &[Sample]) -> Vec<Point> {
+        samples
+            .iter()
+            .filter_map(|sample| {
+                let (desc, values) = match sample.metric {
+                    None => (self.series.desc()[0], &[][..]),
+                    Some(name) => {
+                        let extra = self.extras.iter().find(|e| e.name == name)?;
+                        (extra.series.desc()[0], &sample.labels[..])
+                    }
+                };
+                if desc.variable_labels.len() != values.len() {
+                    return None;
+                }
+
+                let labels = label_pairs(desc)
+                    .into_iter()
+                    .map(|(n, v)| (n.to_string(), v.to_string()))
+                    .chain(
+                        desc.variable_labels
+                            .iter()
+                            .cloned()
+                            .zip(values.iter().cloned()),
+                    )
+                    .collect();
+                Some(Point {
+                    name: desc.fq_name.clone(),
+                    labels,
+                    value: self.scale(sample.value),
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the value scaled like the versions before real units did if legacy_scaling is set.
+    fn scale(&self, v: f64) -> f64 {
+        match self.legacy_scaling {
+            true => legacy_scaled(self.task_name, v),
+            false => v,
+        }
+    }
+
+    /// Updates the series with the samples returned by a run of the task
+    /// according to their metric kinds,
+    /// the gauges of extra metrics missing from the samples are removed.
+    pub(crate) fn update(&self, samples: &[Sample]) {
+        let scale = |v: f64| self.scale(v);
+
+        let mut seen: Vec<HashSet<Vec<String>>> = vec![HashSet::new(); self.extras.len()];
+        for sample in samples {
+            let name = match sample.metric {
+                Some(name) => name,
+                None => {
+                    self.series.collector.update(scale(sample.value));
+                    continue;
+                }
+            };
+
+            let i = match self.extras.iter().position(|e| e.name == name) {
+                Some(i) => i,
+                None => {
+                    warn!(
+                        "task:{} returned an undeclared metric:{}",
+                        self.task_name.name(),
+                        name
+                    );
+                    continue;
+                }
+            };
+            let values: Vec<&str> = sample.labels.iter().map(String::as_str).collect();
+            match self.extras[i]
+                .series
+                .collector
+                .update(&values, scale(sample.value))
+            {
+                Ok(()) => {
+                    seen[i].insert(sample.labels.clone());
+                }
+                Err(e) => warn!(
+                    "task:{} returned an invalid sample of metric:{}, err:{}",
+                    self.task_name.name(),
+                    name,
+                    e
+                ),
+            }
+        }
+
+        let now = unix_now();
+        for (extra, seen) in self.extras.iter().zip(seen) {
+            let mut labels = extra.labels.lock().expect("lock labels failed");
+            // counters and histograms keep accumulating across runs
+            if matches!(extra.series.collector, InstrumentVec::Gauge(_)) {
+                labels.retain(|gone, _| {
+                    if seen.contains(gone) {
+                        return true;
+                    }
+                    let values: Vec<&str> = gone.iter().map(String::as_str).collect();
+                    let _ = extra.series.collector.remove(&values);
+                    false
+                });
+            }
+            for values in seen {
+                labels.entry(values).or_insert(now);
+            }
+        }
+
+        *self.series.updated.lock().expect("lock updated failed") = Some(Instant::now());
+        self.status.lock().expect("lock status failed").samples = samples.to_vec();
+    }
+
+    /// Records a run of the target which took elapsed time
+    /// and failed with the error if any.
+    pub(crate) fn record(&self, elapsed: Duration, error: Option<String>) {
+        let success = error.is_none();
+        let mut status = self.status.lock().expect("lock status failed");
+        match error {
+            None => status.last_success = Some(unix_now()),
+            Some(e) => status.last_error = Some((unix_now(), e)),
+        }
+        drop(status);
+
+        let stats = match &self.stats {
+            Some(stats) => stats,
+            None => return,
+        };
+
+        stats.duration.observe(elapsed.as_secs_f64());
+        if success {
+            stats.successes.inc();
+            stats
+                .last_success
+                .set(OffsetDateTime::now_utc().unix_timestamp());
+            stats.up.set(1);
+        } else {
+            stats.failures.inc();
+            stats.up.set(0);
+        }
+    }
+
+    /// Returns the current values of the target in real units.
+    fn values(&self) -> Values {
+        let desc = &self.series.collector.desc()[0];
+        let status = self.status.lock().expect("lock status failed");
+        let mut extras = vec![];
+        let mut value = None;
+        for sample in &status.samples {
+            let name = match sample.metric {
+                Some(name) => name,
+                None => {
+                    value = Some(sample.value);
+                    continue;
+                }
+            };
+            let names = match self.extras.iter().find(|e| e.name == name) {
+                Some(extra) => &extra.series.collector.desc()[0].variable_labels,
+                None => continue,
+            };
+            extras.push(ExtraValue {
+                metric: name,
+                labels: names.iter().cloned().zip(sample.labels.clone()).collect(),
+                value: sample.value,
+            });
+        }
+
+        Values {
+            task: self.task_name.name(),
+            labels: label_pairs(desc)
+                .into_iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            value,
+            extras,
+            last_success: status.last_success,
+            last_error: status.last_error.as_ref().map(|(_, e)| e.clone()),
+            last_error_time: status.last_error.as_ref().map(|(t, _)| *t),
+        }
+    }
+}
+
+/// An extra metric declared by the task of a target.
+struct ExtraSeries {
+    name: &'static str,
+    series: Series<InstrumentVec>,
+    /// the label values of the series with the unix time each of them was created,
+    /// gauges missing from the latest run are removed.
+    labels: Mutex<HashMap<Vec<String>, f64>>,
+}
+
+/// The series of a target which are exposed according to its stale policy.
+#[derive(Clone)]
+struct Series<C: Collector + Clone> {
+    collector: C,
+    /// how long a value stays fresh, never goes stale if None.
+    max_age: Option<Duration>,
+    stale_policy: StalePolicy,
+    /// when the latest value was set.
+    updated: Arc<Mutex<Option<Instant>>>,
+}
+
+impl<C: Collector + Clone> Series<C> {
+    /// Returns the series of the target sharing the time of the latest update.
+    fn new(
+        collector: C,
+        cfg: &crate::config::Target,
+        updated: &Arc<Mutex<Option<Instant>>>,
+    ) -> Self {
+        Series {
+            collector,
+            max_age: cfg.max_age_ms.map(Duration::from_millis),
+            stale_policy: cfg.stale_policy,
+            updated: updated.clone(),
+        }
+    }
+
+    /// Returns true if the value has not been set within the max age.
+    fn is_stale(&self) -> bool {
+        let max_age = match self.max_age {
+            Some(max_age) => max_age,
+            None => return false,
+        };
+        match *self.updated.lock().expect("lock updated failed") {
+            Some(updated) => updated.elapsed() > max_age,
+            None => true,
+        }
+    }
+}
+
+impl<C: Collector + Clone> Collector for Series<C> {
+    fn desc(&self) -> Vec<&Desc> {
+        self.collector.desc()
+    }
+
+    /// Returns the metric families with the stale policy applied,
+    /// the samples carry the time of the latest update as their timestamps.
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.collector.collect();
+        if let Some(updated) = *self.updated.lock().expect("lock updated failed") {
+            let timestamp_ms = ((unix_now() - updated.elapsed().as_secs_f64()) * 1000.0) as i64;
+            families
+                .iter_mut()
+                .flat_map(|f| f.mut_metric().iter_mut())
+                .for_each(|m| m.set_timestamp_ms(timestamp_ms));
+        }
+
+        let stale = self.is_stale();
+        match self.stale_policy {
+            _ if self.max_age.is_none() => {}
+            StalePolicy::Drop if stale => families.clear(),
+            // counters and histograms have no value to replace
+            StalePolicy::NaN if stale => families
+                .iter_mut()
+                .flat_map(|f| f.mut_metric().iter_mut())
+                .filter(|m| m.has_gauge())
+                .for_each(|m| m.mut_gauge().set_value(f64::NAN)),
+            StalePolicy::Label => {
+                for m in families.iter_mut().flat_map(|f| f.mut_metric().iter_mut()) {
+                    let mut label = LabelPair::default();
+                    label.set_name("stale".to_string());
+                    label.set_value(stale.to_string());
+                    m.mut_label().push(label);
+                    m.mut_label()
+                        .as_mut_slice()
+                        .sort_by(|a, b| a.get_name().cmp(b.get_name()));
+                }
+            }
+            _ => {}
+        }
+        families
+    }
+}
+
+/// The collector of a metric of the kind declared by a task.
+#[derive(Clone)]
+enum Instrument {
+    Gauge(Gauge),
+    Counter(Counter),
+    Histogram(Histogram),
+}
+
+impl Instrument {
+    fn new(kind: Kind, opts: Opts) -> prometheus::Result<Self> {
+        Ok(match kind {
+            Kind::Gauge => Instrument::Gauge(Gauge::with_opts(opts)?),
+            Kind::Counter => Instrument::Counter(Counter::with_opts(opts)?),
+            Kind::Histogram(buckets) => Instrument::Histogram(Histogram::with_opts(
+                HistogramOpts::from(opts).buckets(buckets.to_vec()),
+            )?),
+        })
+    }
+
+    /// Sets a gauge, advances a counter to the running total or observes the value.
+    fn update(&self, v: f64) {
+        match self {
+            Instrument::Gauge(g) => g.set(v),
+            Instrument::Counter(c) => {
+                // a lower total comes from a lagging endpoint and is ignored
+                let current = c.get();
+                if v > current {
+                    c.inc_by(v - current)
+                }
+            }
+            Instrument::Histogram(h) => h.observe(v),
+        }
+    }
+}
+
+impl Collector for Instrument {
+    fn desc(&self) -> Vec<&Desc> {
+        match self {
+            Instrument::Gauge(g) => g.desc(),
+            Instrument::Counter(c) => c.desc(),
+            Instrument::Histogram(h) => h.desc(),
+        }
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        match self {
+            Instrument::Gauge(g) => g.collect(),
+            Instrument::Counter(c) => c.collect(),
+            Instrument::Histogram(h) => h.collect(),
+        }
+    }
+}
+
+/// The collector of a labeled metric of the kind declared by a task.
+#[derive(Clone)]
+enum InstrumentVec {
+    Gauge(GaugeVec),
+    Counter(CounterVec),
+    Histogram(HistogramVec),
+}
+
+impl InstrumentVec {
+    fn new(kind: Kind, opts: Opts, labels: &[&str]) -> prometheus::Result<Self> {
+        Ok(match kind {
+            Kind::Gauge => InstrumentVec::Gauge(GaugeVec::new(opts, labels)?),
+            Kind::Counter => InstrumentVec::Counter(CounterVec::new(opts, labels)?),
+            Kind::Histogram(buckets) => InstrumentVec::Histogram(HistogramVec::new(
+                HistogramOpts::from(opts).buckets(buckets.to_vec()),
+                labels,
+            )?),
+        })
+    }
+
+    /// Updates the series of the label values like Instrument::update.
+    fn update(&self, values: &[&str], v: f64) -> prometheus::Result<()> {
+        let instrument = match self {
+            InstrumentVec::Gauge(g) => Instrument::Gauge(g.get_metric_with_label_values(values)?),
+            InstrumentVec::Counter(c) => {
+                Instrument::Counter(c.get_metric_with_label_values(values)?)
+            }
+            InstrumentVec::Histogram(h) => {
+                Instrument::Histogram(h.get_metric_with_label_values(values)?)
+            }
+        };
+        instrument.update(v);
+        Ok(())
+    }
+
+    /// Removes the series of the label values.
+    fn remove(&self, values: &[&str]) -> prometheus::Result<()> {
+        match self {
+            InstrumentVec::Gauge(g) => g.remove_label_values(values),
+            InstrumentVec::Counter(c) => c.remove_label_values(values),
+            InstrumentVec::Histogram(h) => h.remove_label_values(values),
+        }
+    }
+}
+
+impl Collector for InstrumentVec {
+    fn desc(&self) -> Vec<&Desc> {
+        match self {
+            InstrumentVec::Gauge(g) => g.desc(),
+            InstrumentVec::Counter(c) => c.desc(),
+            InstrumentVec::Histogram(h) => h.desc(),
+        }
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        match self {
+            InstrumentVec::Gauge(g) => g.collect(),
+            InstrumentVec::Counter(c) => c.collect(),
+            InstrumentVec::Histogram(h) => h.collect(),
+        }
+    }
+}
+
+/// Registers the series of the metric into the registry.
+fn register(registry: &Registry, metric: &Metric) -> prometheus::Result<()> {
+    for collector in metric.collectors() {
+        registry.register(collector)?;
+    }
+    Ok(())
+}
+
+/// Returns the const label pairs of the desc.
+fn label_pairs(desc: &Desc) -> Vec<(&str, &str)> {
+    desc.const_label_pairs
+        .iter()
+        .map(|l| (l.get_name(), l.get_value()))
+        .collect()
+}
+
+/// Returns the current unix time in seconds.
+fn unix_now() -> f64 {
+    OffsetDateTime::now_utc().unix_timestamp_nanos() as f64 / 1e9
+}
+
+/// Returns the value scaled into an integer like the versions before real units did.
+fn legacy_scaled(task_name: TaskName, v: f64) -> f64 {
+    match task_name {
+        TaskName::GetPrice => (v * 1e6).round(),
+        TaskName::ConsensusPower => (v * 100.0).trunc(),
+        TaskName::TotalBalanceOfRelayers
+        | TaskName::BridgedBalance
+        | TaskName::BridgedSupply
+        | TaskName::NativeBalance => (v * 1e6).trunc(),
+        TaskName::NetworkFunctional | TaskName::TotalCountOfValidators => v,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Crawler, ExtraOpts, Target};
+
+    /// Returns the number of series of the family.
+    fn count_of(families: &[MetricFamily], name: &str) -> usize {
+        families
+            .iter()
+            .filter(|f| f.get_name() == name)
+            .map(|f| f.get_metric().len())
+            .sum()
+    }
+
+    #[test]
+    fn test_metric_record() {
+        let cfg = Crawler {
+            targets: vec![Target {
+                host_addr: "http://127.0.0.1:8545".to_string(),
+                task_name: TaskName::NativeBalance,
+                extra_opts: Some(ExtraOpts::NativeBalance {
+                    native_address: "0xae13d989dac2f0debff460ac112a837c89baa7cd".to_string(),
+                    decimal: 18,
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let metrics = Metrics::new(&cfg).unwrap();
+        let metric = metrics.get_metric(calculate_hash(&cfg.targets[0])).unwrap();
+        metric.record(Duration::from_millis(100), None);
+        metric.record(Duration::from_millis(300), Some("timed out".to_string()));
+
+        let families = metrics.gather();
+        let family = |name: &str| {
+            families
+                .iter()
+                .find(|f| f.get_name() == name)
+                .unwrap_or_else(|| panic!("{} not found", name))
+                .get_metric()[0]
+                .clone()
+        };
+
+        let up = family("findora_exporter_task_up");
+        assert_eq!(0.0, up.get_gauge().get_value());
+        let labels: Vec<(&str, &str)> = up
+            .get_label()
+            .iter()
+            .map(|l| (l.get_name(), l.get_value()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("addr", "http://127.0.0.1:8545"),
+                ("subject", "0xae13d989dac2f0debff460ac112a837c89baa7cd"),
+                ("task", "native_balance"),
+            ],
+            labels
+        );
+
+        assert_eq!(
+            1.0,
+            family("findora_exporter_task_successes_total")
+                .get_counter()
+                .get_value()
+        );
+        assert_eq!(
+            1.0,
+            family("findora_exporter_task_failures_total")
+                .get_counter()
+                .get_value()
+        );
+        assert_ne!(
+            0.0,
+            family("findora_exporter_task_last_success_timestamp_seconds")
+                .get_gauge()
+                .get_value()
+        );
+        let duration = family("findora_exporter_task_duration_seconds");
+        assert_eq!(2, duration.get_histogram().get_sample_count());
+        assert!((duration.get_histogram().get_sample_sum() - 0.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_metric_stale_policy() {
+        let metric = |policy: StalePolicy| {
+            Metric::new(
+                &Target {
+                    task_name: TaskName::BridgedBalance,
+                    max_age_ms: Some(60000),
+                    stale_policy: policy,
+                    ..Default::default()
+                },
+                &AutoLabels::default(),
             )
-            .context("new get_price failed")?,
+            .unwrap()
+        };
+        let expire = |m: &Metric| {
+            *m.series.updated.lock().unwrap() = Some(Instant::now() - Duration::from_secs(61));
+        };
+        let stale_label = |families: &[MetricFamily]| {
+            families[0].get_metric()[0]
+                .get_label()
+                .iter()
+                .find(|l| l.get_name() == "stale")
+                .map(|l| l.get_value().to_string())
         };
 
-        registry
-            .register(Box::new(metric.clone()))
-            .context("register metric failed")?;
+        // a value which has never been set is stale
+        let m = metric(StalePolicy::Drop);
+        assert!(m.series.collect().is_empty());
+        m.update(&[Sample::new(7.0)]);
+        assert_eq!(
+            7.0,
+            m.series.collect()[0].get_metric()[0]
+                .get_gauge()
+                .get_value()
+        );
+        expire(&m);
+        assert!(m.series.collect().is_empty());
 
-        Ok(Metric { registry, metric })
+        let m = metric(StalePolicy::NaN);
+        m.update(&[Sample::new(7.0)]);
+        assert_eq!(
+            7.0,
+            m.series.collect()[0].get_metric()[0]
+                .get_gauge()
+                .get_value()
+        );
+        expire(&m);
+        assert!(m.series.collect()[0].get_metric()[0]
+            .get_gauge()
+            .get_value()
+            .is_nan());
+
+        let m = metric(StalePolicy::Label);
+        m.update(&[Sample::new(7.0)]);
+        assert_eq!(Some("false".to_string()), stale_label(&m.series.collect()));
+        expire(&m);
+        let families = m.series.collect();
+        assert_eq!(Some("true".to_string()), stale_label(&families));
+        assert_eq!(7.0, families[0].get_metric()[0].get_gauge().get_value());
+
+        // without a max age the value never goes stale
+        let m = Metric::new(&Target::default(), &AutoLabels::default()).unwrap();
+        assert_eq!(1, m.series.collect().len());
+        assert_eq!(None, stale_label(&m.series.collect()));
     }
 
-    fn gather(&self) -> Vec<MetricFamily> {
-        self.registry.gather()
+    #[test]
+    fn test_metrics_reload() {
+        // the series of the same metric name are told apart by the node label
+        let target = |addr: &str, task_name: TaskName| Target {
+            host_addr: addr.to_string(),
+            task_name,
+            registry: Some(crate::config::Registry {
+                prefix: "".to_string(),
+                labels: HashMap::from([("node".to_string(), addr.to_string())]),
+            }),
+            ..Default::default()
+        };
+        let mut cfg = Crawler {
+            targets: vec![
+                target("http://a", TaskName::NetworkFunctional),
+                target("http://b", TaskName::NetworkFunctional),
+                target("http://d", TaskName::NetworkFunctional),
+            ],
+            ..Default::default()
+        };
+        let metrics = Metrics::new(&cfg).unwrap();
+        let get = |t: &Target| metrics.get_metric(calculate_hash(t));
+        let a = get(&cfg.targets[0]).unwrap();
+        let b = get(&cfg.targets[1]).unwrap();
+        a.update(&[Sample::new(7.0)]);
+
+        let removed = cfg.targets.pop().unwrap();
+        cfg.targets[1].max_age_ms = Some(60000);
+        cfg.targets
+            .push(target("http://c", TaskName::TotalCountOfValidators));
+        metrics.reload(&cfg).unwrap();
+
+        // the unchanged target keeps its value
+        assert!(Arc::ptr_eq(&a, &get(&cfg.targets[0]).unwrap()));
+        assert_eq!(
+            7.0,
+            a.series.collect()[0].get_metric()[0]
+                .get_gauge()
+                .get_value()
+        );
+        // the changed target is replaced
+        assert!(!Arc::ptr_eq(&b, &get(&cfg.targets[1]).unwrap()));
+        assert!(get(&cfg.targets[2]).is_ok());
+        assert!(get(&removed).is_err());
+
+        let families = metrics.gather();
+        // b is stale and dropped until its first success
+        assert_eq!(1, count_of(&families, "network_functional"));
+        assert_eq!(1, count_of(&families, "total_count_of_validators"));
+        assert_eq!(3, count_of(&families, "findora_exporter_task_up"));
+
+        // a target which fails to register changes nothing
+        cfg.targets[0].registry = Some(crate::config::Registry {
+            prefix: "".to_string(),
+            labels: HashMap::from([("not valid".to_string(), "a".to_string())]),
+        });
+        assert!(metrics.reload(&cfg).is_err());
+        assert!(Arc::ptr_eq(&a, &get(&cfg.targets[0]).unwrap()));
+
+        // a target conflicting with another one changes nothing
+        cfg.targets[0].registry = Some(crate::config::Registry {
+            prefix: "".to_string(),
+            labels: HashMap::from([
+                ("node".to_string(), "http://b".to_string()),
+                ("host".to_string(), "http://b".to_string()),
+            ]),
+        });
+        assert!(metrics.reload(&cfg).is_err());
+        assert!(Arc::ptr_eq(&a, &get(&cfg.targets[0]).unwrap()));
+        assert_eq!(1, count_of(&metrics.gather(), "network_functional"));
     }
 
-    /// set a value for metric
-    pub(crate) fn set(&self, v: <T as Atomic>::T) {
-        self.metric.set(v)
+    #[test]
+    fn test_metrics_reload_removes_target_series() {
+        let target = |addr: &str| Target {
+            host_addr: addr.to_string(),
+            task_name: TaskName::NetworkFunctional,
+            registry: Some(crate::config::Registry {
+                prefix: "".to_string(),
+                labels: HashMap::from([("node".to_string(), addr.to_string())]),
+            }),
+            ..Default::default()
+        };
+        let mut cfg = Crawler {
+            targets: vec![target("http://a"), target("http://b"), target("http://c")],
+            ..Default::default()
+        };
+        let metrics = Metrics::new(&cfg).unwrap();
+        let endpoints = metrics.endpoint_metrics();
+        for t in &cfg.targets {
+            let labels = task_labels(t);
+            metrics.skip_ticks(&labels, "in_flight", 1);
+            let [task, addr, subject] = labels.each_ref().map(String::as_str);
+            endpoints
+                .info
+                .with_label_values(&[task, addr, subject, addr])
+                .set(1);
+            endpoints
+                .divergence
+                .with_label_values(&[task, addr, subject])
+                .set(0);
+        }
+
+        // a is unchanged, b is changed and c is removed
+        cfg.targets[1].max_age_ms = Some(60000);
+        cfg.targets.pop();
+        metrics.reload(&cfg).unwrap();
+
+        let families = metrics.gather();
+        for name in [
+            "findora_exporter_skipped_ticks_total",
+            "findora_exporter_endpoint_info",
+            "findora_exporter_endpoint_divergence",
+        ] {
+            let addrs: Vec<&str> = families
+                .iter()
+                .filter(|f| f.get_name() == name)
+                .flat_map(|f| f.get_metric())
+                .flat_map(|m| m.get_label())
+                .filter(|l| l.get_name() == "addr")
+                .map(|l| l.get_value())
+                .collect();
+            assert_eq!(vec!["http://a"], addrs, "{}", name);
+        }
+        assert_eq!(1, metrics.skipped_ticks_created.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_metrics_task_labels() {
+        let target = |holder: &str, decimal| Target {
+            host_addr: "http://127.0.0.1:8545".to_string(),
+            task_name: TaskName::BridgedBalance,
+            extra_opts: Some(ExtraOpts::BridgedBalance {
+                erc20handler_address: holder.to_string(),
+                token_address: "0xtoken".to_string(),
+                decimal,
+            }),
+            ..Default::default()
+        };
+        let mut cfg = Crawler {
+            targets: vec![target("0xa", 18), target("0xb", 18)],
+            ..Default::default()
+        };
+        let metrics = Metrics::new(&cfg).unwrap();
+        let b = metrics.get_metric(calculate_hash(&cfg.targets[1])).unwrap();
+        assert_eq!(2, count_of(&metrics.gather(), "findora_exporter_task_up"));
+
+        // removing a keeps the series b updates
+        cfg.targets.remove(0);
+        metrics.reload(&cfg).unwrap();
+        b.record(Duration::from_millis(100), None);
+        let families = metrics.gather();
+        let up = families
+            .iter()
+            .find(|f| f.get_name() == "findora_exporter_task_up")
+            .unwrap()
+            .get_metric();
+        assert_eq!(1, up.len());
+        assert_eq!(1.0, up[0].get_gauge().get_value());
+        assert!(up[0]
+            .get_label()
+            .iter()
+            .any(|l| l.get_name() == "subject" && l.get_value() == "0xtoken/0xb"));
+
+        // targets which only differ in the decimal cannot be told apart
+        cfg.targets.push(target("0xb", 6));
+        assert!(metrics.reload(&cfg).is_err());
+    }
+
+    #[test]
+    fn test_metric_legacy_scaling() {
+        let mut cfg = Crawler {
+            targets: vec![
+                Target {
+                    task_name: TaskName::GetPrice,
+                    ..Default::default()
+                },
+                Target {
+                    task_name: TaskName::BridgedBalance,
+                    ..Default::default()
+                },
+                Target {
+                    task_name: TaskName::ConsensusPower,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let value = |metrics: &Metrics, i: usize| {
+            metrics
+                .get_metric(calculate_hash(&cfg.targets[i]))
+                .unwrap()
+                .series
+                .collect()[0]
+                .get_metric()[0]
+                .get_gauge()
+                .get_value()
+        };
+
+        let metrics = Metrics::new(&cfg).unwrap();
+        for (i, v) in [0.01815, 9.98958012, 0.6789].into_iter().enumerate() {
+            metrics
+                .get_metric(calculate_hash(&cfg.targets[i]))
+                .unwrap()
+                .update(&[Sample::new(v)]);
+        }
+        assert_eq!(0.01815, value(&metrics, 0));
+        assert_eq!(9.98958012, value(&metrics, 1));
+        assert_eq!(0.6789, value(&metrics, 2));
+
+        cfg.legacy_scaling = true;
+        metrics.reload(&cfg).unwrap();
+        for (i, v) in [0.01815, 9.98958012, 0.6789].into_iter().enumerate() {
+            metrics
+                .get_metric(calculate_hash(&cfg.targets[i]))
+                .unwrap()
+                .update(&[Sample::new(v)]);
+        }
+        assert_eq!(18150.0, value(&metrics, 0));
+        assert_eq!(9989580.0, value(&metrics, 1));
+        assert_eq!(67.0, value(&metrics, 2));
+    }
+
+    #[test]
+    fn test_metrics_merge_families() {
+        let target = |native_address: &str, registry: Option<crate::config::Registry>| Target {
+            task_name: TaskName::NativeBalance,
+            extra_opts: Some(ExtraOpts::NativeBalance {
+                native_address: native_address.to_string(),
+                decimal: 18,
+            }),
+            registry,
+            ..Default::default()
+        };
+        let registry = |holder: &str| {
+            Some(crate::config::Registry {
+                prefix: "findora".to_string(),
+                labels: HashMap::from([("holder".to_string(), holder.to_string())]),
+            })
+        };
+        let mut cfg = Crawler {
+            targets: vec![
+                target("0x01", registry("alice")),
+                target("0x02", registry("bob")),
+            ],
+            ..Default::default()
+        };
+
+        let metrics = Metrics::new(&cfg).unwrap();
+        let families = metrics.gather();
+        assert_eq!(
+            1,
+            families
+                .iter()
+                .filter(|f| f.get_name() == "findora_native_balance")
+                .count()
+        );
+        assert_eq!(2, count_of(&families, "findora_native_balance"));
+
+        // the same label values once the holder_address labels are gone
+        cfg.targets[1].registry = registry("alice");
+        assert!(Metrics::new(&cfg).is_ok());
+        cfg.auto_labels.holder_address = false;
+        assert!(Metrics::new(&cfg).is_err());
+        // different label names
+        cfg.targets[1].registry = Some(crate::config::Registry {
+            prefix: "findora".to_string(),
+            labels: HashMap::from([("owner".to_string(), "bob".to_string())]),
+        });
+        assert!(Metrics::new(&cfg).is_err());
+        // the stale label is reserved
+        cfg.targets[1].registry = Some(crate::config::Registry {
+            prefix: "findora".to_string(),
+            labels: HashMap::from([("stale".to_string(), "bob".to_string())]),
+        });
+        assert!(Metrics::new(&cfg).is_err());
+    }
+
+    #[test]
+    fn test_metric_auto_labels() {
+        let target = Target {
+            host_addr: "https://prod-mainnet.prod.findora.org:8545/rpc".to_string(),
+            task_name: TaskName::BridgedBalance,
+            extra_opts: Some(ExtraOpts::BridgedBalance {
+                erc20handler_address: "0x02".to_string(),
+                token_address: "0x01".to_string(),
+                decimal: 18,
+            }),
+            ..Default::default()
+        };
+        let labels = |target: &Target, auto_labels: &AutoLabels| {
+            let metric = Metric::new(target, auto_labels).unwrap();
+            let families = metric.series.collect();
+            families[0].get_metric()[0]
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                .collect::<Vec<_>>()
+        };
+        let pair = |name: &str, value: &str| (name.to_string(), value.to_string());
+
+        assert_eq!(
+            vec![
+                pair("holder_address", "0x02"),
+                pair("host", "https://prod-mainnet.prod.findora.org:8545"),
+                pair("task", "bridged_balance"),
+                pair("token_address", "0x01"),
+            ],
+            labels(&target, &AutoLabels::default())
+        );
+
+        let auto_labels = AutoLabels {
+            host: false,
+            task: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![
+                pair("holder_address", "0x02"),
+                pair("token_address", "0x01")
+            ],
+            labels(&target, &auto_labels)
+        );
+
+        // the labels of the registry take precedence
+        let target = Target {
+            registry: Some(crate::config::Registry {
+                prefix: "".to_string(),
+                labels: HashMap::from([("token_address".to_string(), "USDT".to_string())]),
+            }),
+            ..target
+        };
+        assert_eq!(
+            vec![
+                pair("holder_address", "0x02"),
+                pair("token_address", "USDT")
+            ],
+            labels(&target, &auto_labels)
+        );
+
+        // the host of a task with a fixed upstream is the upstream
+        let target = Target {
+            host_addr: "TEST_FRA_USDT".to_string(),
+            task_name: TaskName::GetPrice,
+            extra_opts: Some(ExtraOpts::GetPrice {
+                currency_pair: "FRA_USDT".to_string(),
+                currency_name: "FRA".to_string(),
+            }),
+            ..Default::default()
+        };
+        assert!(labels(&target, &AutoLabels::default())
+            .contains(&pair("host", "https://api.gateio.ws")));
+    }
+
+    #[test]
+    fn test_metric_extras() {
+        let cfg = Crawler {
+            targets: vec![Target {
+                host_addr: "http://127.0.0.1:8545".to_string(),
+                task_name: TaskName::TotalBalanceOfRelayers,
+                extra_opts: Some(ExtraOpts::TotalBalanceOfRelayers {
+                    bridge_address: "0x01".to_string(),
+                    decimal: 18,
+                }),
+                registry: Some(crate::config::Registry {
+                    prefix: "findora".to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let metrics = Metrics::new(&cfg).unwrap();
+        let metric = metrics.get_metric(calculate_hash(&cfg.targets[0])).unwrap();
+        let relayer =
+            |relayer: &str, v: f64| Sample::extra("relayer_balance", vec![relayer.to_string()], v);
+
+        metric.update(&[Sample::new(3.0), relayer("0x0a", 1.0), relayer("0x0b", 2.0)]);
+        let families = metrics.gather();
+        assert_eq!(1, count_of(&families, "findora_total_balance_of_relayers"));
+        assert_eq!(2, count_of(&families, "findora_relayer_balance"));
+        let family = families
+            .iter()
+            .find(|f| f.get_name() == "findora_relayer_balance")
+            .unwrap();
+        let m = &family.get_metric()[0];
+        assert_eq!(1.0, m.get_gauge().get_value());
+        assert!(m
+            .get_label()
+            .iter()
+            .any(|l| l.get_name() == "relayer" && l.get_value() == "0x0a"));
+        assert!(m
+            .get_label()
+            .iter()
+            .any(|l| l.get_name() == "bridge_address" && l.get_value() == "0x01"));
+
+        // a relayer which is gone is removed
+        metric.update(&[Sample::new(2.0), relayer("0x0b", 2.0)]);
+        assert_eq!(1, count_of(&metrics.gather(), "findora_relayer_balance"));
+
+        // all series of the target are unregistered on reload
+        metrics.reload(&Crawler::default()).unwrap();
+        let families = metrics.gather();
+        assert_eq!(0, count_of(&families, "findora_total_balance_of_relayers"));
+        assert_eq!(0, count_of(&families, "findora_relayer_balance"));
+    }
+
+    #[test]
+    fn test_metric_points() {
+        let target = Target {
+            host_addr: "http://127.0.0.1:8545".to_string(),
+            task_name: TaskName::TotalBalanceOfRelayers,
+            extra_opts: Some(ExtraOpts::TotalBalanceOfRelayers {
+                bridge_address: "0x01".to_string(),
+                decimal: 18,
+            }),
+            ..Default::default()
+        };
+        let metric = Metric::new(&target, &AutoLabels::default()).unwrap();
+
+        let points = metric.points(&[
+            Sample::new(3.0),
+            Sample::extra("relayer_balance", vec!["0x0a".to_string()], 1.0),
+            Sample::extra("relayer_balance", vec![], 1.0),
+            Sample::extra("undeclared", vec![], 1.0),
+        ]);
+        let got: Vec<(&str, f64)> = points.iter().map(|p| (p.name.as_str(), p.value)).collect();
+        assert_eq!(
+            vec![("total_balance_of_relayers", 3.0), ("relayer_balance", 1.0)],
+            got
+        );
+        for label in [("bridge_address", "0x01"), ("relayer", "0x0a")] {
+            assert!(points[1]
+                .labels
+                .contains(&(label.0.to_string(), label.1.to_string())));
+        }
+    }
+
+    #[test]
+    fn test_metrics_values() {
+        let cfg = Crawler {
+            targets: vec![
+                Target {
+                    host_addr: "http://127.0.0.1:8545".to_string(),
+                    task_name: TaskName::TotalBalanceOfRelayers,
+                    extra_opts: Some(ExtraOpts::TotalBalanceOfRelayers {
+                        bridge_address: "0x01".to_string(),
+                        decimal: 18,
+                    }),
+                    ..Default::default()
+                },
+                Target {
+                    host_addr: "http://127.0.0.1:8668".to_string(),
+                    task_name: TaskName::GetPrice,
+                    extra_opts: Some(ExtraOpts::GetPrice {
+                        currency_pair: "FRA_USDT".to_string(),
+                        currency_name: "FRA".to_string(),
+                    }),
+                    ..Default::default()
+                },
+            ],
+            legacy_scaling: true,
+            ..Default::default()
+        };
+        let metrics = Metrics::new(&cfg).unwrap();
+        let relayers = metrics.get_metric(calculate_hash(&cfg.targets[0])).unwrap();
+        relayers.update(&[
+            Sample::new(3.0),
+            Sample::extra("relayer_balance", vec!["0x0a".to_string()], 1.5),
+        ]);
+        relayers.record(Duration::from_millis(100), None);
+        relayers.record(Duration::from_millis(100), Some("timed out".to_string()));
+        let price = metrics.get_metric(calculate_hash(&cfg.targets[1])).unwrap();
+        price.update(&[Sample::new(0.0123)]);
+        price.record(Duration::from_millis(100), None);
+
+        let values = metrics.values();
+        assert_eq!(
+            vec!["get_price", "total_balance_of_relayers"],
+            values.iter().map(|v| v.task).collect::<Vec<_>>()
+        );
+        // real units no matter legacy_scaling
+        assert_eq!(Some(0.0123), values[0].value);
+        assert_eq!(None, values[0].last_error);
+        assert_eq!(
+            Some("FRA_USDT"),
+            values[0].labels.get("currency_pair").map(String::as_str)
+        );
+
+        let v = &values[1];
+        assert_eq!(Some(3.0), v.value);
+        assert_eq!(
+            vec![ExtraValue {
+                metric: "relayer_balance",
+                labels: [("relayer".to_string(), "0x0a".to_string())].into(),
+                value: 1.5,
+            }],
+            v.extras
+        );
+        assert!(v.last_success.is_some());
+        assert_eq!(Some("timed out".to_string()), v.last_error);
+        assert!(v.last_error_time >= v.last_success);
+    }
+
+    #[test]
+    fn test_metric_kinds() {
+        let target = Target {
+            host_addr: "http://127.0.0.1:26657".to_string(),
+            task_name: TaskName::NetworkFunctional,
+            max_age_ms: Some(60000),
+            stale_policy: StalePolicy::NaN,
+            ..Default::default()
+        };
+        let m = Metric::new(&target, &AutoLabels::default()).unwrap();
+        let blocks = |m: &Metric| {
+            m.extras[0].series.collect()[0].get_metric()[0]
+                .get_counter()
+                .get_value()
+        };
+
+        m.update(&[
+            Sample::new(3.0),
+            Sample::extra("blocks_total", vec![], 42.0),
+        ]);
+        assert_eq!(42.0, blocks(&m));
+        // a lagging endpoint does not move the counter backwards
+        m.update(&[
+            Sample::new(3.0),
+            Sample::extra("blocks_total", vec![], 40.0),
+        ]);
+        assert_eq!(42.0, blocks(&m));
+        // a counter keeps its series when a run misses it
+        m.update(&[Sample::new(3.0)]);
+        assert_eq!(42.0, blocks(&m));
+        m.update(&[
+            Sample::new(3.0),
+            Sample::extra("blocks_total", vec![], 45.0),
+        ]);
+        assert_eq!(45.0, blocks(&m));
+
+        // only gauges turn NaN when stale
+        *m.series.updated.lock().unwrap() = Some(Instant::now() - Duration::from_secs(61));
+        assert!(m.series.collect()[0].get_metric()[0]
+            .get_gauge()
+            .get_value()
+            .is_nan());
+        assert_eq!(45.0, blocks(&m));
+
+        let h = InstrumentVec::new(
+            Kind::Histogram(&[1.0, 10.0]),
+            Opts::new("block_interval_seconds", "block interval"),
+            &["host"],
+        )
+        .unwrap();
+        for v in [0.5, 2.0, 20.0] {
+            h.update(&["a"], v).unwrap();
+        }
+        assert!(h.update(&[], 1.0).is_err());
+        let families = h.collect();
+        let histogram = families[0].get_metric()[0].get_histogram();
+        assert_eq!(3, histogram.get_sample_count());
+        assert_eq!(22.5, histogram.get_sample_sum());
+        let counts: Vec<u64> = histogram
+            .get_bucket()
+            .iter()
+            .map(|b| b.get_cumulative_count())
+            .collect();
+        assert_eq!(vec![1, 2], counts);
+    }
+
+    #[test]
+    fn test_metric_overrides() {
+        let target = |metric_name: Option<&str>, help: Option<&str>, unit: Option<&str>| Target {
+            host_addr: "http://127.0.0.1:8545".to_string(),
+            task_name: TaskName::BridgedBalance,
+            extra_opts: Some(ExtraOpts::BridgedBalance {
+                erc20handler_address: "0x02".to_string(),
+                token_address: "0x01".to_string(),
+                decimal: 18,
+            }),
+            registry: Some(crate::config::Registry {
+                prefix: "findora".to_string(),
+                ..Default::default()
+            }),
+            metric_name: metric_name.map(str::to_string),
+            help: help.map(str::to_string),
+            unit: unit.map(str::to_string),
+            ..Default::default()
+        };
+        let family = |target: &Target| {
+            let m = Metric::new(target, &AutoLabels::default()).unwrap();
+            m.update(&[Sample::new(1.0)]);
+            let family = &m.series.collect()[0];
+            (family.get_name().to_string(), family.get_help().to_string())
+        };
+
+        assert_eq!(
+            (
+                "findora_bridged_balance".to_string(),
+                "the token balance of reserving safe on source chain".to_string()
+            ),
+            family(&target(None, None, None))
+        );
+        assert_eq!(
+            (
+                "findora_bridge_reserve_usdt".to_string(),
+                "the USDT reserve of the bridge on BSC".to_string()
+            ),
+            family(&target(
+                Some("bridge_reserve"),
+                Some("the USDT reserve of the bridge on BSC"),
+                Some("usdt")
+            ))
+        );
+        // the unit is not appended twice
+        assert_eq!(
+            "findora_bridge_reserve_usdt",
+            family(&target(Some("bridge_reserve_usdt"), None, Some("usdt"))).0
+        );
+
+        for invalid in [
+            target(Some("bridge-reserve"), None, None),
+            target(Some("2bridge"), None, None),
+            target(Some("bridge:reserve"), None, None),
+            target(None, Some(" "), None),
+            target(None, None, Some("")),
+            target(None, None, Some("us dt")),
+        ] {
+            assert!(Metric::new(&invalid, &AutoLabels::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_metrics_metadata() {
+        let cfg = Crawler {
+            targets: vec![Target {
+                host_addr: "http://127.0.0.1:26657".to_string(),
+                task_name: TaskName::NetworkFunctional,
+                unit: Some("seconds".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let metrics = Metrics::new(&cfg).unwrap();
+        let metric = metrics.get_metric(calculate_hash(&cfg.targets[0])).unwrap();
+        metrics.skip_ticks(&task_labels(&cfg.targets[0]), "in_flight", 1);
+
+        // no timestamp before the first run
+        let families = metrics.gather();
+        let family = |families: &[MetricFamily], name: &str| {
+            families
+                .iter()
+                .find(|f| f.get_name() == name)
+                .cloned()
+                .unwrap()
+        };
+        assert_eq!(
+            0,
+            family(&families, "network_functional_seconds").get_metric()[0].get_timestamp_ms()
+        );
+
+        let before = OffsetDateTime::now_utc().unix_timestamp() * 1000;
+        metric.update(&[
+            Sample::new(3.0),
+            Sample::extra("blocks_total", vec![], 42.0),
+        ]);
+        let families = metrics.gather();
+        let timestamp_ms =
+            family(&families, "network_functional_seconds").get_metric()[0].get_timestamp_ms();
+        assert!(timestamp_ms >= before && timestamp_ms <= before + 2000);
+
+        let metadata = metrics.metadata();
+        assert_eq!(
+            Some(&"seconds".to_string()),
+            metadata.units.get("network_functional_seconds")
+        );
+        let created_of = |name: &str| {
+            let family = family(&families, name);
+            let labels: Vec<(&str, &str)> = family.get_metric()[0]
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name(), l.get_value()))
+                .collect();
+            metadata.created.get(&series_key(name, &labels)).copied()
+        };
+        for name in [
+            "network_functional_seconds",
+            "blocks_total",
+            "findora_exporter_task_successes_total",
+            "findora_exporter_task_duration_seconds",
+            "findora_exporter_skipped_ticks_total",
+        ] {
+            let created = created_of(name).unwrap();
+            assert!(created * 1000.0 >= (before - 2000) as f64, "{}", name);
+        }
     }
 }
@@ -0,0 +1,269 @@
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine};
+use log::{error, info};
+use prometheus::{Encoder, ProtobufEncoder, PROTOBUF_FORMAT};
+use std::{sync::Arc, thread, thread::JoinHandle, time::Duration};
+
+use crate::{
+    config::BasicAuth,
+    crawler::{Scraper, Shutdown},
+    exposition::strip_timestamps,
+    metrics::Metrics,
+};
+
+/// A pusher pushing the metrics to a Prometheus Pushgateway periodically.
+pub(crate) struct Pusher {
+    metrics: Arc<Metrics>,
+    gateway: Gateway,
+    scraper: Option<Arc<dyn Scraper>>,
+    interval: Duration,
+    delete_on_shutdown: bool,
+    shutdown: Arc<Shutdown>,
+}
+
+impl Pusher {
+    /// Returns a Pusher instance.
+    ///
+    /// This new method will not push anything but only returns a Pusher instance.
+    ///
+    /// If a scraper is given, every push scrapes targets before gathering the metrics.
+    pub(crate) fn new(
+        cfg: &crate::config::Pushgateway,
+        metrics: Arc<Metrics>,
+        scraper: Option<Arc<dyn Scraper>>,
+    ) -> Result<Self> {
+        if cfg.job.is_empty() {
+            bail!("the job of pushgateway is empty");
+        }
+        if cfg.interval_ms == 0 {
+            bail!("the interval_ms of pushgateway is zero");
+        }
+
+        let mut url = format!(
+            "{}/metrics/{}",
+            cfg.url.trim_end_matches('/'),
+            grouping_key("job", &cfg.job)
+        );
+        if !cfg.instance.is_empty() {
+            url = format!("{}/{}", url, grouping_key("instance", &cfg.instance));
+        }
+
+        Ok(Pusher {
+            metrics,
+            gateway: Gateway {
+                agent: ureq::AgentBuilder::new()
+                    .timeout(Duration::from_millis(cfg.timeout_ms))
+                    .build(),
+                url,
+                authorization: cfg.basic_auth.as_ref().map(BasicAuth::header),
+            },
+            scraper,
+            interval: Duration::from_millis(cfg.interval_ms),
+            delete_on_shutdown: cfg.delete_on_shutdown,
+            shutdown: Arc::new(Shutdown::default()),
+        })
+    }
+
+    /// Stops pushing, the thread deletes the group before exiting if delete_on_shutdown is set.
+    pub(crate) fn close(&self) {
+        self.shutdown.signal()
+    }
+
+    /// Spawned a new thread to push the metrics every interval until closed.
+    ///
+    /// A failed push is logged and retried on the next interval.
+    pub(crate) fn run(&self) -> Result<JoinHandle<()>> {
+        let metrics = self.metrics.clone();
+        let gateway = self.gateway.clone();
+        let scraper = self.scraper.clone();
+        let interval = self.interval;
+        let delete_on_shutdown = self.delete_on_shutdown;
+        let shutdown = self.shutdown.clone();
+
+        thread::Builder::new()
+            .name("pusher_thread".into())
+            .spawn(move || {
+                loop {
+                    if let Some(scraper) = &scraper {
+                        // a scrape cannot outlast the interval of pushes
+                        scraper.scrape(interval);
+                    }
+                    if let Err(e) = gateway.push(&metrics) {
+                        error!("push to {} failed: {:?}", gateway.url, e);
+                    }
+                    if shutdown.sleep(interval) {
+                        break;
+                    }
+                }
+
+                if delete_on_shutdown {
+                    match gateway.delete() {
+                        Ok(()) => info!("deleted {} from the pushgateway", gateway.url),
+                        Err(e) => error!("delete {} failed: {:?}", gateway.url, e),
+                    }
+                }
+            })
+            .context("pusher thread run failed")
+    }
+}
+
+/// The group of a Pushgateway the metrics are pushed into.
+#[derive(Clone)]
+struct Gateway {
+    agent: ureq::Agent,
+    url: String,
+    /// the value of the Authorization header.
+    authorization: Option<String>,
+}
+
+impl Gateway {
+    /// Replaces the metrics of the group with the gathered ones,
+    /// the Pushgateway rejects samples with timestamps so they are stripped.
+    fn push(&self, metrics: &Metrics) -> Result<()> {
+        let mut families = metrics.gather();
+        strip_timestamps(&mut families);
+        let mut body = vec![];
+        ProtobufEncoder::new()
+            .encode(&families, &mut body)
+            .context("encode metric families failed")?;
+
+        self.request("PUT")
+            .set("Content-Type", PROTOBUF_FORMAT)
+            .send_bytes(&body)
+            .context("ureq call failed")?;
+        Ok(())
+    }
+
+    /// Deletes the group with all its metrics.
+    fn delete(&self) -> Result<()> {
+        self.request("DELETE").call().context("ureq call failed")?;
+        Ok(())
+    }
+
+    fn request(&self, method: &str) -> ureq::Request {
+        let request = self.agent.request(method, &self.url);
+        match &self.authorization {
+            Some(authorization) => request.set("Authorization", authorization),
+            None => request,
+        }
+    }
+}
+
+/// Returns the URL path segments of a grouping key,
+/// values not safe in a path are base64 encoded as the Pushgateway allows.
+fn grouping_key(name: &str, value: &str) -> String {
+    if value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+    {
+        return format!("{}/{}", name, value);
+    }
+    format!(
+        "{}@base64/{}",
+        name,
+        general_purpose::URL_SAFE.encode(value)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{BasicAuth, Crawler, Pushgateway},
+        test_util::serve,
+    };
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_grouping_key() {
+        assert_eq!(
+            "job/findora_exporter",
+            grouping_key("job", "findora_exporter")
+        );
+        assert_eq!(
+            "instance/127.0.0.1:9090",
+            grouping_key("instance", "127.0.0.1:9090")
+        );
+        assert_eq!(
+            "instance@base64/YnJpZGdlLzE=",
+            grouping_key("instance", "bridge/1")
+        );
+    }
+
+    #[test]
+    fn test_pusher() {
+        let (tx, rx) = mpsc::channel();
+        let url = serve(move |request| {
+            let header = |name: &'static str| {
+                request
+                    .headers()
+                    .iter()
+                    .find(|h| h.field.equiv(name))
+                    .map(|h| h.value.to_string())
+            };
+            let got = (
+                request.method().to_string(),
+                request.url().to_string(),
+                header("Authorization"),
+                header("Content-Type"),
+            );
+            let mut body = vec![];
+            request.as_reader().read_to_end(&mut body).unwrap();
+            tx.send((got, body)).unwrap();
+            tiny_http::Response::empty(200).boxed()
+        });
+
+        let metrics = Arc::new(Metrics::new(&Crawler::default()).unwrap());
+        let cfg = Pushgateway {
+            url: format!("{}/", url),
+            instance: "bridge-1".to_string(),
+            interval_ms: 50,
+            basic_auth: Some(BasicAuth {
+                username: "findora".to_string(),
+                password: "secret".to_string(),
+            }),
+            delete_on_shutdown: true,
+            ..Default::default()
+        };
+        let pusher = Pusher::new(&cfg, metrics.clone(), None).unwrap();
+        let handle = pusher.run().unwrap();
+
+        let authorization = Some("Basic ZmluZG9yYTpzZWNyZXQ=".to_string());
+        let ((method, path, auth, content_type), body) =
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!("PUT", method);
+        assert_eq!("/metrics/job/findora_exporter/instance/bridge-1", path);
+        assert_eq!(authorization, auth);
+        assert_eq!(Some(PROTOBUF_FORMAT.to_string()), content_type);
+        let mut want = vec![];
+        ProtobufEncoder::new()
+            .encode(&metrics.gather(), &mut want)
+            .unwrap();
+        assert_eq!(want, body);
+
+        // pushes again after the interval
+        let ((method, ..), _) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!("PUT", method);
+
+        pusher.close();
+        handle.join().unwrap();
+        let (path, auth) = loop {
+            let ((method, path, auth, _), _) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            if method == "DELETE" {
+                break (path, auth);
+            }
+        };
+        assert_eq!("/metrics/job/findora_exporter/instance/bridge-1", path);
+        assert_eq!(authorization, auth);
+
+        assert!(Pusher::new(
+            &Pushgateway {
+                job: "".to_string(),
+                ..Default::default()
+            },
+            metrics,
+            None
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,546 @@
+use anyhow::{bail, Context, Result};
+use log::{error, warn};
+use prometheus::proto::{MetricFamily, MetricType};
+use std::{collections::VecDeque, sync::Arc, thread, thread::JoinHandle, time::Duration};
+use time::OffsetDateTime;
+
+use crate::{
+    client::{backoff, is_retryable},
+    config::BasicAuth,
+    crawler::{Scraper, Shutdown},
+    exposition::{format_float, strip_timestamps},
+    metrics::Metrics,
+};
+
+/// A writer sending the metrics to a Prometheus remote_write endpoint periodically.
+pub(crate) struct Writer {
+    metrics: Arc<Metrics>,
+    sender: Sender,
+    scraper: Option<Arc<dyn Scraper>>,
+    interval: Duration,
+    max_pending: usize,
+    shutdown: Arc<Shutdown>,
+}
+
+impl Writer {
+    /// Returns a Writer instance.
+    ///
+    /// This new method will not send anything but only returns a Writer instance.
+    ///
+    /// If a scraper is given, every batch scrapes targets before gathering the metrics.
+    pub(crate) fn new(
+        cfg: &crate::config::RemoteWrite,
+        metrics: Arc<Metrics>,
+        scraper: Option<Arc<dyn Scraper>>,
+    ) -> Result<Self> {
+        if cfg.url.is_empty() {
+            bail!("the url of remote_write is empty");
+        }
+        if cfg.interval_ms == 0 {
+            bail!("the interval_ms of remote_write is zero");
+        }
+        if cfg.max_pending == 0 {
+            bail!("the max_pending of remote_write is zero");
+        }
+
+        Ok(Writer {
+            metrics,
+            sender: Sender {
+                agent: ureq::AgentBuilder::new()
+                    .timeout_connect(Duration::from_millis(cfg.http.connect_timeout_ms))
+                    .timeout_read(Duration::from_millis(cfg.http.read_timeout_ms))
+                    .timeout(Duration::from_millis(cfg.http.timeout_ms))
+                    .build(),
+                url: cfg.url.clone(),
+                authorization: cfg.basic_auth.as_ref().map(BasicAuth::header),
+                max_retries: cfg.http.max_retries,
+                backoff_initial: Duration::from_millis(cfg.http.backoff_initial_ms),
+                backoff_max: Duration::from_millis(cfg.http.backoff_max_ms),
+            },
+            scraper,
+            interval: Duration::from_millis(cfg.interval_ms),
+            max_pending: cfg.max_pending,
+            shutdown: Arc::new(Shutdown::default()),
+        })
+    }
+
+    /// Stops writing, the thread tries to send the pending batches once more before exiting.
+    pub(crate) fn close(&self) {
+        self.shutdown.signal()
+    }
+
+    /// Spawned a new thread to send a batch of the metrics every interval until closed.
+    ///
+    /// Batches failed to send are kept and resent in order on the next interval.
+    pub(crate) fn run(&self) -> Result<JoinHandle<()>> {
+        let metrics = self.metrics.clone();
+        let sender = self.sender.clone();
+        let scraper = self.scraper.clone();
+        let interval = self.interval;
+        let mut pending = Pending::new(self.max_pending);
+        let shutdown = self.shutdown.clone();
+
+        thread::Builder::new()
+            .name("remote_write_thread".into())
+            .spawn(move || {
+                loop {
+                    if let Some(scraper) = &scraper {
+                        // a scrape cannot outlast the interval of batches
+                        scraper.scrape(interval);
+                    }
+                    let batch = encode(&gather(&metrics), unix_now_ms());
+                    match snap::raw::Encoder::new().compress_vec(&batch) {
+                        Ok(batch) => {
+                            if pending.push(batch) {
+                                warn!(
+                                    "remote_write has {} pending batches, dropped the oldest one",
+                                    pending.max
+                                );
+                            }
+                        }
+                        Err(e) => error!("compress the remote_write batch failed: {}", e),
+                    }
+                    sender.flush(&mut pending, &shutdown);
+                    if shutdown.sleep(interval) {
+                        break;
+                    }
+                }
+
+                // the shutdown is signaled so nothing is retried
+                sender.flush(&mut pending, &shutdown);
+                if !pending.batches.is_empty() {
+                    error!(
+                        "remote_write dropped {} pending batches on shutdown",
+                        pending.batches.len()
+                    );
+                }
+            })
+            .context("remote_write thread run failed")
+    }
+}
+
+/// The compressed batches waiting to be sent, the oldest ones are dropped when it is full.
+struct Pending {
+    batches: VecDeque<Vec<u8>>,
+    max: usize,
+}
+
+impl Pending {
+    fn new(max: usize) -> Self {
+        Pending {
+            batches: VecDeque::new(),
+            max,
+        }
+    }
+
+    /// Appends the batch, returns true if the oldest one was dropped for it.
+    fn push(&mut self, batch: Vec<u8>) -> bool {
+        self.batches.push_back(batch);
+        if self.batches.len() > self.max {
+            self.batches.pop_front();
+            return true;
+        }
+        false
+    }
+}
+
+/// The remote_write endpoint the batches are sent to.
+#[derive(Clone)]
+struct Sender {
+    agent: ureq::Agent,
+    url: String,
+    /// the value of the Authorization header.
+    authorization: Option<String>,
+    max_retries: u32,
+    backoff_initial: Duration,
+    backoff_max: Duration,
+}
+
+impl Sender {
+    /// Sends the pending batches in order until one fails with a retryable error,
+    /// a batch rejected by the endpoint is dropped as resending cannot succeed.
+    fn flush(&self, pending: &mut Pending, shutdown: &Shutdown) {
+        while let Some(batch) = pending.batches.front() {
+            match self.send(batch, shutdown) {
+                Ok(()) => {}
+                Err(e) if is_retryable(&e) => {
+                    error!("remote_write to {} failed, will resend: {}", self.url, e);
+                    return;
+                }
+                Err(e) => error!("remote_write to {} rejected a batch: {}", self.url, e),
+            }
+            pending.batches.pop_front();
+        }
+    }
+
+    /// Sends the batch until it succeeds, fails with a non-retryable error,
+    /// runs out of retries or the shutdown is signaled.
+    #[allow(clippy::result_large_err)]
+    fn send(&self, batch: &[u8], shutdown: &Shutdown) -> std::result::Result<(), ureq::Error> {
+        let mut request = self
+            .agent
+            .post(&self.url)
+            .set("Content-Encoding", "snappy")
+            .set("Content-Type", "application/x-protobuf")
+            .set("X-Prometheus-Remote-Write-Version", "0.1.0");
+        if let Some(authorization) = &self.authorization {
+            request = request.set("Authorization", authorization);
+        }
+
+        let mut attempt = 0;
+        loop {
+            match request.clone().send_bytes(batch) {
+                Ok(_) => return Ok(()),
+                Err(e) if attempt < self.max_retries && is_retryable(&e) => {
+                    if shutdown.sleep(backoff(self.backoff_initial, self.backoff_max, attempt)) {
+                        return Err(e);
+                    }
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A sample of a series flattened from a metric family,
+/// the labels include __name__ and are sorted by name.
+#[derive(Debug, PartialEq)]
+struct TimeSeries {
+    labels: Vec<(String, String)>,
+    value: f64,
+    timestamp_ms: i64,
+}
+
+/// Returns the gathered metric families without the timestamps of samples,
+/// so every batch carries the values at the time it is made like a scrape does.
+///
+/// The crawl time of a series cannot be kept, a stale NaN would otherwise be sent
+/// at the timestamp its last real value was already sent at,
+/// which the endpoint rejects as a duplicate sample along with the whole batch.
+fn gather(metrics: &Metrics) -> Vec<MetricFamily> {
+    let mut families = metrics.gather();
+    strip_timestamps(&mut families);
+    families
+}
+
+/// Flattens the metric families into time series like Prometheus stores them,
+/// samples without a timestamp get now_ms.
+fn time_series(families: &[MetricFamily], now_ms: i64) -> Vec<TimeSeries> {
+    let mut series = vec![];
+    for mf in families {
+        let name = mf.get_name();
+        for m in mf.get_metric() {
+            let timestamp_ms = if m.has_timestamp_ms() {
+                m.get_timestamp_ms()
+            } else {
+                now_ms
+            };
+            let mut push = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                let mut labels: Vec<(String, String)> = m
+                    .get_label()
+                    .iter()
+                    .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                    .chain(extra.map(|(n, v)| (n.to_string(), v)))
+                    .collect();
+                labels.push(("__name__".to_string(), format!("{}{}", name, suffix)));
+                labels.sort();
+                series.push(TimeSeries {
+                    labels,
+                    value,
+                    timestamp_ms,
+                });
+            };
+
+            match mf.get_field_type() {
+                MetricType::COUNTER => push("", None, m.get_counter().get_value()),
+                MetricType::GAUGE => push("", None, m.get_gauge().get_value()),
+                MetricType::UNTYPED => push("", None, m.get_untyped().get_value()),
+                MetricType::HISTOGRAM => {
+                    let h = m.get_histogram();
+                    for b in h.get_bucket() {
+                        let le = format_float(b.get_upper_bound());
+                        push("_bucket", Some(("le", le)), b.get_cumulative_count() as f64);
+                    }
+                    if !matches!(h.get_bucket().last(), Some(b) if b.get_upper_bound().is_infinite())
+                    {
+                        let le = format_float(f64::INFINITY);
+                        push("_bucket", Some(("le", le)), h.get_sample_count() as f64);
+                    }
+                    push("_sum", None, h.get_sample_sum());
+                    push("_count", None, h.get_sample_count() as f64);
+                }
+                MetricType::SUMMARY => {
+                    let s = m.get_summary();
+                    for q in s.get_quantile() {
+                        let quantile = format_float(q.get_quantile());
+                        push("", Some(("quantile", quantile)), q.get_value());
+                    }
+                    push("_sum", None, s.get_sample_sum());
+                    push("_count", None, s.get_sample_count() as f64);
+                }
+            }
+        }
+    }
+    series
+}
+
+/// Encodes the metric families into an uncompressed remote_write WriteRequest message.
+///
+/// The message is small enough to be written by hand:
+/// WriteRequest{timeseries=1}, TimeSeries{labels=1, samples=2},
+/// Label{name=1, value=2} and Sample{value=1, timestamp=2}.
+fn encode(families: &[MetricFamily], now_ms: i64) -> Vec<u8> {
+    let mut buf = vec![];
+    for ts in time_series(families, now_ms) {
+        let mut series = vec![];
+        for (name, value) in &ts.labels {
+            let mut label = vec![];
+            write_bytes(&mut label, 1, name.as_bytes());
+            write_bytes(&mut label, 2, value.as_bytes());
+            write_bytes(&mut series, 1, &label);
+        }
+        let mut sample = vec![];
+        // a double is the wire type 1 and an int64 is the wire type 0
+        write_varint(&mut sample, 1 << 3 | 1);
+        sample.extend_from_slice(&ts.value.to_le_bytes());
+        write_varint(&mut sample, 2 << 3);
+        write_varint(&mut sample, ts.timestamp_ms as u64);
+        write_bytes(&mut series, 2, &sample);
+        write_bytes(&mut buf, 1, &series);
+    }
+    buf
+}
+
+/// Writes a length delimited field, the wire type 2.
+fn write_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_varint(buf, field << 3 | 2);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn unix_now_ms() -> i64 {
+    (OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{BasicAuth, Crawler, RemoteWrite},
+        test_util::serve,
+    };
+    use prometheus::{Counter, Histogram, HistogramOpts, Opts, Registry};
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_write_varint() {
+        let cases: Vec<(u64, Vec<u8>)> = vec![
+            (0, vec![0x00]),
+            (1, vec![0x01]),
+            (127, vec![0x7f]),
+            (128, vec![0x80, 0x01]),
+            (300, vec![0xac, 0x02]),
+            (1_700_000_000_000, vec![0x80, 0xd0, 0x95, 0xff, 0xbc, 0x31]),
+        ];
+        for (v, want) in cases {
+            let mut buf = vec![];
+            write_varint(&mut buf, v);
+            assert_eq!(want, buf, "{}", v);
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let registry = Registry::new();
+        let counter =
+            Counter::with_opts(Opts::new("blocks_total", "help").const_label("Host", "a")).unwrap();
+        counter.inc_by(3.0);
+        registry.register(Box::new(counter)).unwrap();
+        let histogram =
+            Histogram::with_opts(HistogramOpts::new("took", "help").buckets(vec![1.0])).unwrap();
+        histogram.observe(0.5);
+        histogram.observe(2.0);
+        registry.register(Box::new(histogram)).unwrap();
+        let mut families = registry.gather();
+        families[0].mut_metric()[0].set_timestamp_ms(42);
+
+        let labels = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect()
+        };
+        let series = time_series(&families, 1000);
+        assert_eq!(
+            vec![
+                TimeSeries {
+                    labels: labels(&[("Host", "a"), ("__name__", "blocks_total")]),
+                    value: 3.0,
+                    timestamp_ms: 42,
+                },
+                TimeSeries {
+                    labels: labels(&[("__name__", "took_bucket"), ("le", "1")]),
+                    value: 1.0,
+                    timestamp_ms: 1000,
+                },
+                TimeSeries {
+                    labels: labels(&[("__name__", "took_bucket"), ("le", "+Inf")]),
+                    value: 2.0,
+                    timestamp_ms: 1000,
+                },
+                TimeSeries {
+                    labels: labels(&[("__name__", "took_sum")]),
+                    value: 2.5,
+                    timestamp_ms: 1000,
+                },
+                TimeSeries {
+                    labels: labels(&[("__name__", "took_count")]),
+                    value: 2.0,
+                    timestamp_ms: 1000,
+                },
+            ],
+            series
+        );
+
+        let mut want = vec![0x0a, 0x32]; // timeseries, 50 bytes
+        want.extend_from_slice(&[0x0a, 0x09, 0x0a, 0x04]); // label, name
+        want.extend_from_slice(b"Host");
+        want.extend_from_slice(&[0x12, 0x01, b'a']); // value
+        want.extend_from_slice(&[0x0a, 0x18, 0x0a, 0x08]); // label, name
+        want.extend_from_slice(b"__name__");
+        want.extend_from_slice(&[0x12, 0x0c]); // value
+        want.extend_from_slice(b"blocks_total");
+        want.extend_from_slice(&[0x12, 0x0b, 0x09]); // sample, value
+        want.extend_from_slice(&3.0f64.to_le_bytes());
+        want.extend_from_slice(&[0x10, 42]); // timestamp
+        assert_eq!(want, encode(&families[..1], 1000));
+    }
+
+    #[test]
+    fn test_gather_stale_nan() {
+        let cfg = Crawler {
+            targets: vec![crate::config::Target {
+                host_addr: "http://127.0.0.1:26657".to_string(),
+                task_name: crate::config::TaskName::NetworkFunctional,
+                max_age_ms: Some(1),
+                stale_policy: crate::config::StalePolicy::NaN,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let metrics = Metrics::new(&cfg).unwrap();
+        metrics
+            .get_metric(crate::utils::calculate_hash(&cfg.targets[0]))
+            .unwrap()
+            .update(&[crate::tasks::Sample::new(7.0)]);
+        thread::sleep(Duration::from_millis(10));
+
+        let series = time_series(&gather(&metrics), 1000);
+        let stale = series
+            .iter()
+            .find(|ts| {
+                ts.labels
+                    .contains(&("__name__".to_string(), "network_functional".to_string()))
+            })
+            .unwrap();
+        assert!(stale.value.is_nan());
+        // the stale NaN is sent at the time of the batch, not at the time of the last real value
+        assert!(series.iter().all(|ts| ts.timestamp_ms == 1000));
+    }
+
+    #[test]
+    fn test_pending() {
+        let mut pending = Pending::new(2);
+        assert!(!pending.push(vec![1]));
+        assert!(!pending.push(vec![2]));
+        assert!(pending.push(vec![3]));
+        assert_eq!(vec![vec![2], vec![3]], Vec::from(pending.batches));
+    }
+
+    #[test]
+    fn test_writer() {
+        let (tx, rx) = mpsc::channel();
+        let mut i = 0;
+        let url = serve(move |request| {
+            let header = |name: &'static str| {
+                request
+                    .headers()
+                    .iter()
+                    .find(|h| h.field.equiv(name))
+                    .map(|h| h.value.to_string())
+            };
+            let got = (
+                request.url().to_string(),
+                header("Authorization"),
+                header("Content-Encoding"),
+                header("Content-Type"),
+                header("X-Prometheus-Remote-Write-Version"),
+            );
+            let mut body = vec![];
+            request.as_reader().read_to_end(&mut body).unwrap();
+            tx.send((got, body)).unwrap();
+            // the first batch fails once for testing the retry
+            i += 1;
+            let status = if i == 1 { 500 } else { 204 };
+            tiny_http::Response::empty(status).boxed()
+        });
+        let url = format!("{}/api/v1/push", url);
+
+        let metrics = Arc::new(Metrics::new(&Crawler::default()).unwrap());
+        let mut cfg = RemoteWrite {
+            url,
+            interval_ms: 50,
+            basic_auth: Some(BasicAuth {
+                username: "findora".to_string(),
+                password: "secret".to_string(),
+            }),
+            ..Default::default()
+        };
+        cfg.http.backoff_initial_ms = 10;
+        let writer = Writer::new(&cfg, metrics.clone(), None).unwrap();
+        let handle = writer.run().unwrap();
+
+        let (got, first) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            (
+                "/api/v1/push".to_string(),
+                Some("Basic ZmluZG9yYTpzZWNyZXQ=".to_string()),
+                Some("snappy".to_string()),
+                Some("application/x-protobuf".to_string()),
+                Some("0.1.0".to_string()),
+            ),
+            got
+        );
+        let (_, retried) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(first, retried);
+
+        let batch = snap::raw::Decoder::new().decompress_vec(&first).unwrap();
+        let name = b"findora_exporter_config_reload_success";
+        assert!(batch.windows(name.len()).any(|w| w == name));
+
+        // sends again after the interval
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        writer.close();
+        handle.join().unwrap();
+
+        assert!(Writer::new(
+            &RemoteWrite {
+                max_pending: 0,
+                ..Default::default()
+            },
+            metrics,
+            None
+        )
+        .is_err());
+    }
+}
@@ -1,32 +1,56 @@
 use anyhow::{Context, Result};
 use log::error;
-use prometheus::{core::Atomic, TextEncoder};
-use std::{sync::Arc, thread, thread::JoinHandle};
+use prometheus::{Encoder, ProtobufEncoder, TextEncoder};
+use std::{sync::Arc, thread, thread::JoinHandle, time::Duration};
+use tiny_http::ResponseBox;
 
-/// A server instance to listen to an IPv4 address and only serve the /metrics path for Prometheus usage.
-pub(crate) struct Server<T: Atomic> {
-    metrics: Arc<crate::metrics::Metrics<T>>,
+use crate::{
+    crawler::Scraper,
+    exposition::{encode_openmetrics, strip_timestamps, Format},
+    metrics::{Metrics, Values},
+    utils::percent_decode,
+};
+
+/// The header Prometheus sends for telling how long it waits for a scrape.
+const SCRAPE_TIMEOUT_HEADER: &str = "X-Prometheus-Scrape-Timeout-Seconds";
+
+/// The time left for encoding and responding when scraping on demand.
+const SCRAPE_TIMEOUT_MARGIN: Duration = Duration::from_millis(500);
+
+/// The path serving the current values of targets as JSON.
+const VALUES_PATH: &str = "/api/v1/values";
+
+/// A server instance to listen to an IPv4 address and serve the /metrics path for Prometheus usage,
+/// beside the current values of targets as JSON.
+pub(crate) struct Server {
+    metrics: Arc<crate::metrics::Metrics>,
     server: Arc<tiny_http::Server>,
+    scraper: Option<Arc<dyn Scraper>>,
+    scrape_timeout: Duration,
+    timestamps: bool,
 }
 
-impl<T> Server<T>
-where
-    T: Atomic + 'static,
-{
+impl Server {
     /// Returns a Server instance.
     ///
     /// This new method will not execute anything but only returns a Server instance.
     /// The server only serves http protocol,
     /// and will Panics on server binding if any error occurs.
+    ///
+    /// If a scraper is given, every request of /metrics scrapes targets before responding.
     pub(crate) fn new(
         cfg: &crate::config::Server,
-        metrics: Arc<crate::metrics::Metrics<T>>,
+        metrics: Arc<crate::metrics::Metrics>,
+        scraper: Option<Arc<dyn Scraper>>,
     ) -> Self {
         Server {
             metrics,
             server: Arc::new(
                 tiny_http::Server::http(&cfg.listen_addr).expect("server binding failed"),
             ),
+            scraper,
+            scrape_timeout: Duration::from_millis(cfg.scrape_timeout_ms),
+            timestamps: cfg.timestamps,
         }
     }
 
@@ -36,26 +60,39 @@ where
     }
 
     /// Spawned a new thread to listen to a specific address and port.
-    /// Serving
+    /// Serving only
     /// 1. GET method
-    /// 2. /metrics path
-    /// only
+    /// 2. /metrics and /api/v1/values paths
+    ///
+    /// The format of /metrics is negotiated by the Accept header,
+    /// see exposition::Format.
+    /// /api/v1/values returns the current values of targets as JSON,
+    /// see values_response.
     ///
     /// returns 403 status code on other requests.
-    /// returns 500 status code on encoding JSON failure.
+    /// returns 500 status code on encoding failure.
     pub(crate) fn run(&self) -> Result<JoinHandle<()>> {
         let server = self.server.clone();
         let metrics = self.metrics.clone();
+        let scraper = self.scraper.clone();
+        let default_timeout = self.scrape_timeout;
+        let timestamps = self.timestamps;
 
         thread::Builder::new()
             .name("server_thread".into())
             .spawn(move || {
                 // consume every prometheus scrape request one by one
                 for request in server.incoming_requests() {
-                    // for prometheus usage, only handle
+                    let (path, query) =
+                        request.url().split_once('?').unwrap_or((request.url(), ""));
+                    let (path, query) = (path.to_string(), query.to_string());
+
+                    // only handle
                     // 1. method == GET
-                    // 2. url path == /metrics
-                    if request.method().as_str() != "GET" || request.url() != "/metrics" {
+                    // 2. url path == /metrics or /api/v1/values
+                    if request.method().as_str() != "GET"
+                        || (path != "/metrics" && path != VALUES_PATH)
+                    {
                         let response = tiny_http::Response::empty(403);
                         if let Err(e) = request.respond(response) {
                             error!("respond failed: {}", e);
@@ -63,13 +100,27 @@ where
                         continue;
                     }
 
-                    let encoder = TextEncoder::new();
-                    let response = match encoder.encode_to_string(&metrics.gather()) {
-                        Ok(v) => tiny_http::Response::from_string(v).boxed(),
-                        Err(e) => {
-                            error!("encode to string failed: {}", e);
-                            tiny_http::Response::empty(500).boxed()
-                        }
+                    if let Some(scraper) = &scraper {
+                        let timeout = request
+                            .headers()
+                            .iter()
+                            .find(|h| h.field.equiv(SCRAPE_TIMEOUT_HEADER))
+                            .and_then(|h| parse_scrape_timeout(h.value.as_str()))
+                            .unwrap_or(default_timeout);
+                        scraper.scrape(timeout);
+                    }
+
+                    let response = if path == VALUES_PATH {
+                        values_response(&metrics, &query)
+                    } else {
+                        let format = Format::negotiate(
+                            request
+                                .headers()
+                                .iter()
+                                .find(|h| h.field.equiv("Accept"))
+                                .map(|h| h.value.as_str()),
+                        );
+                        metrics_response(&metrics, format, timestamps)
                     };
                     if let Err(e) = request.respond(response) {
                         error!("respond failed: {}", e);
@@ -79,3 +130,239 @@ where
             .context("server thread run failed")
     }
 }
+
+/// Returns the response of /metrics encoded in the format,
+/// the timestamps of samples are only kept if timestamps is set.
+fn metrics_response(metrics: &Metrics, format: Format, timestamps: bool) -> ResponseBox {
+    let mut families = metrics.gather();
+    if !timestamps {
+        strip_timestamps(&mut families);
+    }
+    let mut encoded = vec![];
+    let result = match format {
+        Format::Text => TextEncoder::new().encode(&families, &mut encoded),
+        Format::OpenMetrics => {
+            encoded = encode_openmetrics(&families, &metrics.metadata()).into();
+            Ok(())
+        }
+        Format::Protobuf => ProtobufEncoder::new().encode(&families, &mut encoded),
+    };
+    match result {
+        Ok(()) => with_content_type(encoded, format.content_type()),
+        Err(e) => {
+            error!("encode {:?} failed: {}", format, e);
+            tiny_http::Response::empty(500).boxed()
+        }
+    }
+}
+
+/// Returns the response of /api/v1/values,
+/// a JSON object of the current values of the targets matching the filters of the query.
+///
+/// The query filters targets by `task=<task name>`
+/// and any number of `label=<name>:<value>` which all have to match,
+/// returns 400 status code on an invalid query.
+fn values_response(metrics: &Metrics, query: &str) -> ResponseBox {
+    let mut task = None;
+    let mut labels = vec![];
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = match percent_decode(value) {
+            Some(value) => value,
+            None => return tiny_http::Response::empty(400).boxed(),
+        };
+        match (key, value.split_once(':')) {
+            ("task", _) => task = Some(value),
+            ("label", Some((name, value))) => labels.push((name.to_string(), value.to_string())),
+            _ => return tiny_http::Response::empty(400).boxed(),
+        }
+    }
+
+    let values: Vec<Values> = metrics
+        .values()
+        .into_iter()
+        .filter(|v| task.as_deref().is_none_or(|t| t == v.task))
+        .filter(|v| {
+            labels
+                .iter()
+                .all(|(name, value)| v.labels.get(name) == Some(value))
+        })
+        .collect();
+    match serde_json::to_vec(&serde_json::json!({ "targets": values })) {
+        Ok(body) => with_content_type(body, "application/json"),
+        Err(e) => {
+            error!("encode values failed: {}", e);
+            tiny_http::Response::empty(500).boxed()
+        }
+    }
+}
+
+fn with_content_type(body: Vec<u8>, content_type: &str) -> ResponseBox {
+    let content_type = tiny_http::Header::from_bytes("Content-Type", content_type)
+        .expect("content type header is valid");
+    tiny_http::Response::from_data(body)
+        .with_header(content_type)
+        .boxed()
+}
+
+/// Returns the time an on-demand scrape can take from the value of the scrape timeout header,
+/// which leaves a margin for responding before Prometheus gives up.
+fn parse_scrape_timeout(v: &str) -> Option<Duration> {
+    let seconds: f64 = v.trim().parse().ok()?;
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(seconds).saturating_sub(SCRAPE_TIMEOUT_MARGIN))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_parse_scrape_timeout() {
+        assert_eq!(
+            Some(Duration::from_millis(9500)),
+            parse_scrape_timeout("10")
+        );
+        assert_eq!(
+            Some(Duration::from_millis(1000)),
+            parse_scrape_timeout(" 1.5 ")
+        );
+        assert_eq!(Some(Duration::ZERO), parse_scrape_timeout("0.2"));
+        assert_eq!(None, parse_scrape_timeout("0"));
+        assert_eq!(None, parse_scrape_timeout("-1"));
+        assert_eq!(None, parse_scrape_timeout("NaN"));
+        assert_eq!(None, parse_scrape_timeout("soon"));
+    }
+
+    #[test]
+    fn test_server_negotiates_format() {
+        let cfg = crate::config::Server {
+            listen_addr: "127.0.0.1:0".to_string(),
+            ..Default::default()
+        };
+        let metrics =
+            Arc::new(crate::metrics::Metrics::new(&crate::config::Crawler::default()).unwrap());
+        let server = Server::new(&cfg, metrics.clone(), None);
+        let addr = format!("http://{}/metrics", server.server.server_addr());
+        let handle = server.run().unwrap();
+
+        let resp = ureq::get(&addr).call().unwrap();
+        assert_eq!(
+            prometheus::TEXT_FORMAT,
+            resp.header("Content-Type").unwrap()
+        );
+        assert!(!resp.into_string().unwrap().contains("# EOF"));
+
+        let resp = ureq::get(&addr)
+            .set(
+                "Accept",
+                "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5",
+            )
+            .call()
+            .unwrap();
+        assert_eq!(
+            Format::OpenMetrics.content_type(),
+            resp.header("Content-Type").unwrap()
+        );
+        let body = resp.into_string().unwrap();
+        assert!(body.contains("# TYPE findora_exporter_config_reload_success gauge"));
+        assert!(body.ends_with("# EOF\n"));
+
+        let resp = ureq::get(&addr)
+            .set(
+                "Accept",
+                "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3",
+            )
+            .call()
+            .unwrap();
+        assert_eq!(
+            prometheus::PROTOBUF_FORMAT,
+            resp.header("Content-Type").unwrap()
+        );
+        let mut body = vec![];
+        resp.into_reader().read_to_end(&mut body).unwrap();
+        let mut want = vec![];
+        ProtobufEncoder::new()
+            .encode(&metrics.gather(), &mut want)
+            .unwrap();
+        assert_eq!(want, body);
+
+        server.close();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_server_serves_values() {
+        let target = |addr: &str, task_name| crate::config::Target {
+            host_addr: addr.to_string(),
+            task_name,
+            ..Default::default()
+        };
+        let crawler = crate::config::Crawler {
+            targets: vec![
+                target(
+                    "http://127.0.0.1:26657",
+                    crate::config::TaskName::NetworkFunctional,
+                ),
+                target(
+                    "http://127.0.0.1:26658",
+                    crate::config::TaskName::TotalCountOfValidators,
+                ),
+            ],
+            ..Default::default()
+        };
+        let metrics = Arc::new(Metrics::new(&crawler).unwrap());
+        let metric = metrics
+            .get_metric(crate::utils::calculate_hash(&crawler.targets[0]))
+            .unwrap();
+        metric.update(&[crate::tasks::Sample::new(1.5)]);
+        metric.record(Duration::from_millis(10), None);
+
+        let cfg = crate::config::Server {
+            listen_addr: "127.0.0.1:0".to_string(),
+            ..Default::default()
+        };
+        let server = Server::new(&cfg, metrics, None);
+        let addr = format!("http://{}", server.server.server_addr());
+        let handle = server.run().unwrap();
+        let targets = |query: &str| -> Vec<serde_json::Value> {
+            let resp = ureq::get(&format!("{}{}{}", addr, VALUES_PATH, query))
+                .call()
+                .unwrap();
+            assert_eq!("application/json", resp.header("Content-Type").unwrap());
+            let body: serde_json::Value = resp.into_json().unwrap();
+            body["targets"].as_array().unwrap().clone()
+        };
+
+        assert_eq!(2, targets("").len());
+        let got = targets("?task=network_functional");
+        assert_eq!(1, got.len());
+        assert_eq!(1.5, got[0]["value"]);
+        assert_eq!("http://127.0.0.1:26657", got[0]["labels"]["host"]);
+        assert!(got[0]["last_success"].is_f64());
+        assert!(got[0]["last_error"].is_null());
+
+        let got = targets("?label=host%3Ahttp%3A%2F%2F127.0.0.1%3A26658");
+        assert_eq!(1, got.len());
+        assert_eq!("total_count_of_validators", got[0]["task"]);
+        assert!(got[0]["value"].is_null());
+        assert!(targets("?task=network_functional&label=host:http://127.0.0.1:26658").is_empty());
+
+        for (path, code) in [
+            (format!("{}?label=host", VALUES_PATH), 400),
+            (format!("{}?symbol=FRA", VALUES_PATH), 400),
+            ("/api/v1/other".to_string(), 403),
+        ] {
+            match ureq::get(&format!("{}{}", addr, path)).call() {
+                Err(ureq::Error::Status(got, _)) => assert_eq!(code, got, "{}", path),
+                other => panic!("{}: {:?}", path, other.map(|r| r.status())),
+            }
+        }
+
+        server.close();
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,379 @@
+use anyhow::{bail, Context, Result};
+use log::error;
+use std::{
+    io::Write,
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Mutex,
+    time::Duration,
+};
+use time::OffsetDateTime;
+
+use crate::config::{LabelStyle, SinkFormat, SinkProtocol};
+
+/// The largest UDP payload StatsD and Graphite receivers take safely,
+/// which fits into a common MTU without fragmentation.
+const MAX_DATAGRAM: usize = 1432;
+
+/// A value of a crawl result named and labeled like the series it updates.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Point {
+    pub(crate) name: String,
+    pub(crate) labels: Vec<(String, String)>,
+    pub(crate) value: f64,
+}
+
+/// The output sinks every crawl result is emitted to beside the Prometheus server.
+#[derive(Default)]
+pub(crate) struct Sinks {
+    sinks: Vec<Sink>,
+}
+
+impl Sinks {
+    /// Returns a Sinks instance of the configured sinks,
+    /// TCP sinks connect on the first emit and reconnect after a failed one.
+    pub(crate) fn new(cfgs: &[crate::config::Sink]) -> Result<Self> {
+        let sinks = cfgs.iter().map(Sink::new).collect::<Result<_>>()?;
+        Ok(Sinks { sinks })
+    }
+
+    /// Emits the points to every sink, a failed sink is logged and skipped.
+    ///
+    /// Points whose values are not finite are left out as neither format can carry them.
+    pub(crate) fn emit(&self, points: &[Point]) {
+        if self.sinks.is_empty() {
+            return;
+        }
+
+        let points: Vec<&Point> = points.iter().filter(|p| p.value.is_finite()).collect();
+        let timestamp = OffsetDateTime::now_utc().unix_timestamp();
+        for sink in &self.sinks {
+            let lines: Vec<String> = points
+                .iter()
+                .map(|p| sink.format_line(p, timestamp))
+                .collect();
+            if lines.is_empty() {
+                continue;
+            }
+            if let Err(e) = sink.send(&lines) {
+                error!("emit to sink {} failed: {:?}", sink.addr, e);
+            }
+        }
+    }
+}
+
+/// A sink writing lines of its format to a receiver.
+struct Sink {
+    format: SinkFormat,
+    labels: LabelStyle,
+    prefix: String,
+    addr: SocketAddr,
+    timeout: Duration,
+    conn: Mutex<Conn>,
+}
+
+enum Conn {
+    Udp(UdpSocket),
+    /// None until connected or after a failed write.
+    Tcp(Option<TcpStream>),
+}
+
+impl Sink {
+    fn new(cfg: &crate::config::Sink) -> Result<Self> {
+        let addr = cfg
+            .addr
+            .to_socket_addrs()
+            .with_context(|| format!("resolve sink addr failed: {}", cfg.addr))?
+            .next()
+            .with_context(|| format!("sink addr resolved to nothing: {}", cfg.addr))?;
+        if cfg.timeout_ms == 0 {
+            bail!("the timeout_ms of sink {} is zero", cfg.addr);
+        }
+
+        let conn = match cfg.protocol {
+            SinkProtocol::Udp => {
+                let local = match addr {
+                    SocketAddr::V4(_) => "0.0.0.0:0",
+                    SocketAddr::V6(_) => "[::]:0",
+                };
+                let socket = UdpSocket::bind(local).context("bind udp socket failed")?;
+                socket
+                    .connect(addr)
+                    .with_context(|| format!("connect udp socket failed: {}", addr))?;
+                Conn::Udp(socket)
+            }
+            SinkProtocol::Tcp => Conn::Tcp(None),
+        };
+
+        Ok(Sink {
+            format: cfg.format,
+            labels: cfg.labels,
+            prefix: cfg.prefix.clone(),
+            addr,
+            timeout: Duration::from_millis(cfg.timeout_ms),
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Returns the line of the point in the sink's format.
+    ///
+    /// A negative StatsD gauge is preceded by a zero one,
+    /// otherwise StatsD takes the sign as a decrement of the previous value.
+    fn format_line(&self, p: &Point, timestamp: i64) -> String {
+        let mut name = p.name.clone();
+        if !self.prefix.is_empty() {
+            name = format!("{}.{}", self.prefix, name);
+        }
+        let labels: Vec<(String, String)> = p
+            .labels
+            .iter()
+            .map(|(n, v)| (path_component(n), tag_value(v)))
+            .collect();
+        let path: String = labels
+            .iter()
+            .map(|(n, v)| format!(".{}.{}", n, path_component(v)))
+            .collect();
+        let statsd = |name: &str, tags: &str| {
+            let gauge = format!("{}:{}|g{}\n", name, p.value, tags);
+            match p.value < 0.0 {
+                true => format!("{}:0|g{}\n{}", name, tags, gauge),
+                false => gauge,
+            }
+        };
+
+        match (self.format, self.labels) {
+            (SinkFormat::Graphite, LabelStyle::Path) => {
+                format!("{}{} {} {}\n", name, path, p.value, timestamp)
+            }
+            (SinkFormat::Graphite, LabelStyle::Tags) => {
+                let tags: String = labels
+                    .iter()
+                    .map(|(n, v)| format!(";{}={}", n, v))
+                    .collect();
+                format!("{}{} {} {}\n", name, tags, p.value, timestamp)
+            }
+            (SinkFormat::Statsd, LabelStyle::Path) => statsd(&format!("{}{}", name, path), ""),
+            (SinkFormat::Statsd, LabelStyle::Tags) if labels.is_empty() => statsd(&name, ""),
+            (SinkFormat::Statsd, LabelStyle::Tags) => {
+                let tags: Vec<String> =
+                    labels.iter().map(|(n, v)| format!("{}:{}", n, v)).collect();
+                statsd(&name, &format!("|#{}", tags.join(",")))
+            }
+        }
+    }
+
+    /// Sends the lines of the points, UDP sinks send them in datagrams of at most MAX_DATAGRAM bytes.
+    fn send(&self, lines: &[String]) -> Result<()> {
+        let mut conn = self.conn.lock().expect("lock sink conn failed");
+        match &mut *conn {
+            Conn::Udp(socket) => {
+                for datagram in datagrams(lines, MAX_DATAGRAM) {
+                    socket
+                        .send(datagram.as_bytes())
+                        .context("udp send failed")?;
+                }
+            }
+            Conn::Tcp(stream) => {
+                if stream.is_none() {
+                    let s = TcpStream::connect_timeout(&self.addr, self.timeout)
+                        .context("tcp connect failed")?;
+                    s.set_write_timeout(Some(self.timeout))
+                        .context("tcp set write timeout failed")?;
+                    *stream = Some(s);
+                }
+                if let Some(s) = stream {
+                    if let Err(e) = s.write_all(lines.concat().as_bytes()) {
+                        // reconnects on the next emit
+                        *stream = None;
+                        return Err(e).context("tcp write failed");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Packs the lines of the points into datagrams of at most max bytes,
+/// the lines of a point are never split and a point longer than max is a datagram of its own.
+fn datagrams(lines: &[String], max: usize) -> Vec<String> {
+    let mut datagrams = vec![];
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + line.len() > max {
+            datagrams.push(std::mem::take(&mut datagram));
+        }
+        datagram.push_str(line);
+    }
+    if !datagram.is_empty() {
+        datagrams.push(datagram);
+    }
+    datagrams
+}
+
+/// Returns the value as a single component of a dotted path,
+/// every character other than letters, digits, '-' and '_' becomes '_'.
+fn path_component(v: &str) -> String {
+    v.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Returns the value as a tag value both Graphite and DogStatsD accept,
+/// the separators of their tags become '_'.
+fn tag_value(v: &str) -> String {
+    v.chars()
+        .map(|c| match c {
+            ';' | '~' | ',' | '|' | '#' | '=' | ' ' | '\n' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Sink as SinkConfig;
+    use std::{io::Read, net::TcpListener};
+
+    fn point(value: f64) -> Point {
+        Point {
+            name: "relayer_balance".to_string(),
+            labels: vec![
+                (
+                    "host".to_string(),
+                    "https://prod.findora.org:8545".to_string(),
+                ),
+                ("relayer".to_string(), "0xab".to_string()),
+            ],
+            value,
+        }
+    }
+
+    #[test]
+    fn test_format_line() {
+        let sink = |format, labels, prefix: &str| {
+            Sink::new(&SinkConfig {
+                format,
+                labels,
+                protocol: SinkProtocol::Udp,
+                prefix: prefix.to_string(),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+
+        assert_eq!(
+            "findora.relayer_balance.host.https___prod_findora_org_8545.relayer.0xab 1.5 1700000000\n",
+            sink(SinkFormat::Graphite, LabelStyle::Path, "findora").format_line(&point(1.5), 1700000000)
+        );
+        assert_eq!(
+            "relayer_balance;host=https://prod.findora.org:8545;relayer=0xab 1.5 1700000000\n",
+            sink(SinkFormat::Graphite, LabelStyle::Tags, "").format_line(&point(1.5), 1700000000)
+        );
+        assert_eq!(
+            "relayer_balance.host.https___prod_findora_org_8545.relayer.0xab:1.5|g\n",
+            sink(SinkFormat::Statsd, LabelStyle::Path, "").format_line(&point(1.5), 0)
+        );
+        assert_eq!(
+            "relayer_balance:1.5|g|#host:https://prod.findora.org:8545,relayer:0xab\n",
+            sink(SinkFormat::Statsd, LabelStyle::Tags, "").format_line(&point(1.5), 0)
+        );
+        assert_eq!(
+            "relayer_balance:0|g|#host:https://prod.findora.org:8545,relayer:0xab\n\
+             relayer_balance:-2|g|#host:https://prod.findora.org:8545,relayer:0xab\n",
+            sink(SinkFormat::Statsd, LabelStyle::Tags, "").format_line(&point(-2.0), 0)
+        );
+
+        let mut p = point(3.0);
+        p.labels.clear();
+        assert_eq!(
+            "relayer_balance:3|g\n",
+            sink(SinkFormat::Statsd, LabelStyle::Tags, "").format_line(&p, 0)
+        );
+    }
+
+    #[test]
+    fn test_sinks_emit() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let sinks = Sinks::new(&[
+            SinkConfig {
+                format: SinkFormat::Statsd,
+                protocol: SinkProtocol::Udp,
+                addr: udp.local_addr().unwrap().to_string(),
+                labels: LabelStyle::Tags,
+                ..Default::default()
+            },
+            SinkConfig {
+                addr: tcp.local_addr().unwrap().to_string(),
+                ..Default::default()
+            },
+        ])
+        .unwrap();
+        sinks.emit(&[point(1.5), point(f64::NAN)]);
+        sinks.emit(&[point(2.5)]);
+
+        let mut buf = [0; 1024];
+        let n = udp.recv(&mut buf).unwrap();
+        assert_eq!(
+            "relayer_balance:1.5|g|#host:https://prod.findora.org:8545,relayer:0xab\n",
+            String::from_utf8_lossy(&buf[..n])
+        );
+
+        // both emits share the connection
+        drop(sinks);
+        let mut got = String::new();
+        tcp.accept().unwrap().0.read_to_string(&mut got).unwrap();
+        let values: Vec<&str> = got.lines().map(|l| l.split(' ').nth(1).unwrap()).collect();
+        assert_eq!(vec!["1.5", "2.5"], values);
+
+        assert!(Sinks::new(&[SinkConfig {
+            addr: "nowhere".to_string(),
+            ..Default::default()
+        }])
+        .is_err());
+    }
+
+    #[test]
+    fn test_datagrams() {
+        let lines: Vec<String> = ["aaaa\n", "bbb\n", "cc\n", "dddddddd\n", "e\n"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(
+            vec!["aaaa\nbbb\n", "cc\n", "dddddddd\n", "e\n"],
+            datagrams(&lines, 9)
+        );
+        assert!(datagrams(&[], 9).is_empty());
+
+        // many relayers do not go over a single datagram
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let sinks = Sinks::new(&[SinkConfig {
+            format: SinkFormat::Statsd,
+            protocol: SinkProtocol::Udp,
+            addr: udp.local_addr().unwrap().to_string(),
+            labels: LabelStyle::Tags,
+            ..Default::default()
+        }])
+        .unwrap();
+        let points: Vec<Point> = (0..100).map(|i| point(i as f64)).collect();
+        sinks.emit(&points);
+
+        let mut got = 0;
+        let mut buf = [0; 65536];
+        while got < points.len() {
+            let n = udp.recv(&mut buf).unwrap();
+            assert!(n <= MAX_DATAGRAM);
+            got += String::from_utf8_lossy(&buf[..n]).lines().count();
+        }
+        assert_eq!(points.len(), got);
+    }
+}
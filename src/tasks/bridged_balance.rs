@@ -1,11 +1,14 @@
-use crate::config::ExtraOpts;
-use crate::utils::{diff_of_decimal_18, toi64_div_10pow12};
+use crate::utils::to_f64_with_decimal;
+use crate::{client::Client, config::ExtraOpts, tasks::Sample};
 
 use anyhow::{bail, Context, Result};
-use prometheus::core::Number;
 use serde_json::Value;
 
-pub(crate) fn bridged_balance<N: Number>(addr: &str, opts: &Option<ExtraOpts>) -> Result<N> {
+pub(crate) fn bridged_balance(
+    client: &Client,
+    addr: &str,
+    opts: &Option<ExtraOpts>,
+) -> Result<Vec<Sample>> {
     let (handler_addr, token_addr, decimal) = match opts {
         Some(ExtraOpts::BridgedBalance {
             erc20handler_address,
@@ -20,35 +23,31 @@ pub(crate) fn bridged_balance<N: Number>(addr: &str, opts: &Option<ExtraOpts>) -
         }
     };
 
-    let data: Value = ureq::post(addr)
-        .send_json(ureq::json!({
-            "method":"eth_call",
-            "jsonrpc":"2.0",
-            "id":0,
-            "params":[
-                {
-                    // keccak256("balanceOf(address)")[:8] = "70a08231"
-                    // https://eips.ethereum.org/EIPS/eip-20#balanceOf
-                    //
-                    // 0x + function signature(8) + padding(erc20Handler)(64)
-                    "data":format!("0x70a08231{:0>64}", handler_addr.trim_start_matches("0x")),
-                    "to":token_addr
-                },
-                "latest"
-            ],
-        }))
+    let data: Value = client
+        .post_json(
+            addr,
+            &ureq::json!({
+                "method":"eth_call",
+                "jsonrpc":"2.0",
+                "id":0,
+                "params":[
+                    {
+                        // keccak256("balanceOf(address)")[:8] = "70a08231"
+                        // https://eips.ethereum.org/EIPS/eip-20#balanceOf
+                        //
+                        // 0x + function signature(8) + padding(erc20Handler)(64)
+                        "data":format!("0x70a08231{:0>64}", handler_addr.trim_start_matches("0x")),
+                        "to":token_addr
+                    },
+                    "latest"
+                ],
+            }),
+        )
         .with_context(|| {
             format!(
                 "requesting balanceOf ureq call failed, addr:{:?}, opts:{:?}",
                 addr, opts
             )
-        })?
-        .into_json()
-        .with_context(|| {
-            format!(
-                "requesting balanceOf ureq json failed, addr:{:?}, opts:{:?}",
-                addr, opts
-            )
         })?;
 
     let balance = &data["result"];
@@ -77,10 +76,7 @@ pub(crate) fn bridged_balance<N: Number>(addr: &str, opts: &Option<ExtraOpts>) -
         ),
     };
 
-    Ok(N::from_i64(toi64_div_10pow12(
-        balance,
-        diff_of_decimal_18(decimal),
-    )))
+    Ok(vec![Sample::new(to_f64_with_decimal(balance, decimal))])
 }
 
 #[cfg(test)]
@@ -89,7 +85,8 @@ mod tests {
 
     #[test]
     fn test_bridged_balance() {
-        assert!(bridged_balance::<u64>(
+        assert!(bridged_balance(
+            &Client::default(),
             "https://data-seed-prebsc-1-s1.binance.org:8545",
             &Some(ExtraOpts::BridgedBalance {
                 erc20handler_address: "0xe2b65e624bBb5513fF805d225258D7A92b0f62C4".to_string(),
@@ -1,11 +1,14 @@
-use crate::config::ExtraOpts;
-use crate::utils::{diff_of_decimal_18, toi64_div_10pow12};
+use crate::utils::to_f64_with_decimal;
+use crate::{client::Client, config::ExtraOpts, tasks::Sample};
 
 use anyhow::{bail, Context, Result};
-use prometheus::core::Number;
 use serde_json::Value;
 
-pub(crate) fn bridged_supply<N: Number>(addr: &str, opts: &Option<ExtraOpts>) -> Result<N> {
+pub(crate) fn bridged_supply(
+    client: &Client,
+    addr: &str,
+    opts: &Option<ExtraOpts>,
+) -> Result<Vec<Sample>> {
     let (token_addr, decimal) = match opts {
         Some(ExtraOpts::BridgedSupply {
             token_address,
@@ -16,35 +19,31 @@ pub(crate) fn bridged_supply<N: Number>(addr: &str, opts: &Option<ExtraOpts>) ->
         }
     };
 
-    let data: Value = ureq::post(addr)
-        .send_json(ureq::json!({
-            "method":"eth_call",
-            "jsonrpc":"2.0",
-            "id":0,
-            "params":[
-                {
-                    // keccak256("totalSupply()")[:8] = "18160ddd"
-                    // https://eips.ethereum.org/EIPS/eip-20#totalSupply
-                    //
-                    // 0x + function signature(8)
-                    "data":"0x18160ddd",
-                    "to":token_addr
-                },
-                "latest"
-            ],
-        }))
+    let data: Value = client
+        .post_json(
+            addr,
+            &ureq::json!({
+                "method":"eth_call",
+                "jsonrpc":"2.0",
+                "id":0,
+                "params":[
+                    {
+                        // keccak256("totalSupply()")[:8] = "18160ddd"
+                        // https://eips.ethereum.org/EIPS/eip-20#totalSupply
+                        //
+                        // 0x + function signature(8)
+                        "data":"0x18160ddd",
+                        "to":token_addr
+                    },
+                    "latest"
+                ],
+            }),
+        )
         .with_context(|| {
             format!(
                 "requesting totalSupply ureq call failed, addr:{:?}, opts:{:?}",
                 addr, opts
             )
-        })?
-        .into_json()
-        .with_context(|| {
-            format!(
-                "requesting totalSupply ureq json failed, addr:{:?}, opts:{:?}",
-                addr, opts
-            )
         })?;
 
     let balance = &data["result"];
@@ -73,10 +72,7 @@ pub(crate) fn bridged_supply<N: Number>(addr: &str, opts: &Option<ExtraOpts>) ->
         ),
     };
 
-    Ok(N::from_i64(toi64_div_10pow12(
-        balance,
-        diff_of_decimal_18(decimal),
-    )))
+    Ok(vec![Sample::new(to_f64_with_decimal(balance, decimal))])
 }
 
 #[cfg(test)]
@@ -85,7 +81,8 @@ mod tests {
 
     #[test]
     fn test_bridged_supply() {
-        assert!(bridged_supply::<u64>(
+        assert!(bridged_supply(
+            &Client::default(),
             "https://data-seed-prebsc-1-s1.binance.org:8545",
             &Some(ExtraOpts::BridgedSupply {
                 token_address: "0xbbb9d97e925922EDFcBc9B7dE0E8e1092383D096".to_string(),
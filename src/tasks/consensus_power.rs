@@ -1,15 +1,16 @@
-use crate::config::ExtraOpts;
+use crate::{client::Client, config::ExtraOpts, tasks::Sample};
 
 use anyhow::{bail, Context, Result};
-use prometheus::core::Number;
 use serde_json::Value;
 
-pub(crate) fn consensus_power<N: Number>(addr: &str, _opts: &Option<ExtraOpts>) -> Result<N> {
-    let data: Value = ureq::get(&format!("{}/dump_consensus_state", addr))
-        .call()
-        .with_context(|| format!("ureq call failed, addr:{:?}", addr))?
-        .into_json()
-        .with_context(|| format!("ureq json failed, addr:{:?}", addr))?;
+pub(crate) fn consensus_power(
+    client: &Client,
+    addr: &str,
+    _opts: &Option<ExtraOpts>,
+) -> Result<Vec<Sample>> {
+    let data: Value = client
+        .get_json(&format!("{}/dump_consensus_state", addr))
+        .with_context(|| format!("ureq call failed, addr:{:?}", addr))?;
 
     let power = &data["result"]["round_state"]["last_commit"]["votes_bit_array"];
     if power.is_null() {
@@ -32,7 +33,7 @@ pub(crate) fn consensus_power<N: Number>(addr: &str, _opts: &Option<ExtraOpts>)
                     addr
                 )
             }
-            (&power[pos + 2..n - 1]).to_string()
+            power[pos + 2..n - 1].to_string()
         }
         None => bail!("power cannot find = symbol, addr:{:?}", addr),
     };
@@ -41,7 +42,7 @@ pub(crate) fn consensus_power<N: Number>(addr: &str, _opts: &Option<ExtraOpts>)
         .parse()
         .with_context(|| format!("power:{} convert to f64 failed, addr:{:?}", power, addr))?;
 
-    Ok(N::from_i64((power * 100.0f64) as i64))
+    Ok(vec![Sample::new(power)])
 }
 
 #[cfg(test)]
@@ -50,8 +51,11 @@ mod tests {
 
     #[test]
     fn test_task_consensus_power() {
-        assert!(
-            consensus_power::<u64>("https://prod-mainnet.prod.findora.org:26657", &None).is_ok()
+        assert!(consensus_power(
+            &Client::default(),
+            "https://prod-mainnet.prod.findora.org:26657",
+            &None
         )
+        .is_ok())
     }
 }
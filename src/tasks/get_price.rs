@@ -1,10 +1,16 @@
-use crate::config::ExtraOpts;
+use crate::{client::Client, config::ExtraOpts, tasks::Sample};
 
 use anyhow::{bail, Context, Result};
-use prometheus::core::Number;
 use serde_json::Value;
 
-pub(crate) fn get_price<N: Number>(addr: &str, opts: &Option<ExtraOpts>) -> Result<N> {
+/// The upstream every target of the task requests, its host_addr is only a name.
+pub(crate) const UPSTREAM: &str = "https://api.gateio.ws";
+
+pub(crate) fn get_price(
+    client: &Client,
+    addr: &str,
+    opts: &Option<ExtraOpts>,
+) -> Result<Vec<Sample>> {
     let (currency_pair, currency_name) = match opts {
         Some(ExtraOpts::GetPrice {
             currency_pair,
@@ -22,26 +28,16 @@ pub(crate) fn get_price<N: Number>(addr: &str, opts: &Option<ExtraOpts>) -> Resu
     // [[unix_timestamp, trading_volume, close_price, highest_price, lowest_price, open_price]]
     // [["1645749900","2839.79160470986265","0.01815","0.01897","0.01793","0.01889"]]
     let path = format!(
-        "https://api.gateio.ws/api/v4/spot/candlesticks?interval=15m&limit=1&currency_pair={}",
-        currency_pair
+        "{}/api/v4/spot/candlesticks?interval=15m&limit=1&currency_pair={}",
+        UPSTREAM, currency_pair
     );
 
-    let data: Value = ureq::get(&path)
-        .set("Accept", "application/json")
-        .call()
-        .with_context(|| {
-            format!(
-                "requesting gate.io call failed, currency_pair:{:?}, currency_name:{:?}",
-                currency_pair, currency_name,
-            )
-        })?
-        .into_json()
-        .with_context(|| {
-            format!(
-                "requesting gate.io json failed, currency_pair:{:?}, currency_name:{:?}",
-                currency_pair, currency_name,
-            )
-        })?;
+    let data: Value = client.get_json(&path).with_context(|| {
+        format!(
+            "requesting gate.io call failed, currency_pair:{:?}, currency_name:{:?}",
+            currency_pair, currency_name,
+        )
+    })?;
 
     if !data.is_array() || !data[0].is_array() {
         bail!(
@@ -96,7 +92,7 @@ pub(crate) fn get_price<N: Number>(addr: &str, opts: &Option<ExtraOpts>) -> Resu
         )
     })?;
 
-    Ok(N::from_i64((price * 1000000.0).round() as i64))
+    Ok(vec![Sample::new(price)])
 }
 
 #[cfg(test)]
@@ -105,7 +101,8 @@ mod tests {
 
     #[test]
     fn test_get_price() {
-        assert!(get_price::<u64>(
+        assert!(get_price(
+            &Client::default(),
             "TEST_FRA_USDT",
             &Some(ExtraOpts::GetPrice {
                 currency_pair: "FRA_USDT".to_string(),
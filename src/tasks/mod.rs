@@ -14,3 +14,149 @@ mod native_balance;
 pub(crate) use native_balance::native_balance;
 mod get_price;
 pub(crate) use get_price::get_price;
+
+use crate::{
+    client::Client,
+    config::{ExtraOpts, TaskName},
+};
+
+use anyhow::Result;
+
+pub(crate) type TaskFn = fn(&Client, &str, &Option<ExtraOpts>) -> Result<Vec<Sample>>;
+
+/// A value returned by a task.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Sample {
+    /// the name of an extra metric declared by the task, None for the task's own metric.
+    pub(crate) metric: Option<&'static str>,
+    /// the values of the labels of the extra metric.
+    pub(crate) labels: Vec<String>,
+    pub(crate) value: f64,
+}
+
+impl Sample {
+    /// Returns a sample of the task's own metric.
+    pub(crate) fn new(value: f64) -> Self {
+        Sample {
+            metric: None,
+            labels: vec![],
+            value,
+        }
+    }
+
+    /// Returns a sample of an extra metric declared by the task.
+    pub(crate) fn extra(metric: &'static str, labels: Vec<String>, value: f64) -> Self {
+        Sample {
+            metric: Some(metric),
+            labels,
+            value,
+        }
+    }
+}
+
+/// The kind of a metric declared by a task.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Kind {
+    /// a sample sets the value.
+    Gauge,
+    /// a sample is the running total, the counter only moves forward to it.
+    Counter,
+    /// every sample is observed into the buckets, no task declares one yet.
+    #[allow(dead_code)]
+    Histogram(&'static [f64]),
+}
+
+/// A metric of labeled samples declared by a task besides its own metric.
+pub(crate) struct Extra {
+    pub(crate) name: &'static str,
+    pub(crate) help: &'static str,
+    pub(crate) kind: Kind,
+    pub(crate) labels: &'static [&'static str],
+}
+
+/// The definition of a task.
+pub(crate) struct Definition {
+    /// the name of the task's own metric.
+    pub(crate) name: &'static str,
+    pub(crate) help: &'static str,
+    pub(crate) kind: Kind,
+    pub(crate) run: TaskFn,
+    pub(crate) extras: &'static [Extra],
+    /// the address the task requests whatever the addr of its target, None if it requests the addr.
+    pub(crate) upstream: Option<&'static str>,
+}
+
+impl Definition {
+    /// Returns the address the task requests for a target of the addr.
+    pub(crate) fn upstream<'a>(&self, addr: &'a str) -> &'a str {
+        self.upstream.unwrap_or(addr)
+    }
+}
+
+/// Returns the definition of the task.
+pub(crate) fn definition(task_name: TaskName) -> Definition {
+    let (help, run, extras): (&str, TaskFn, &[Extra]) = match task_name {
+        TaskName::ConsensusPower => (
+            "the ratio of the current consensus network voting power",
+            consensus_power,
+            &[],
+        ),
+        TaskName::NetworkFunctional => (
+            "subtraction of seconds of the latest block time with the current time",
+            network_functional,
+            &[Extra {
+                name: "blocks_total",
+                help: "the number of blocks produced by the consensus network",
+                kind: Kind::Counter,
+                labels: &[],
+            }],
+        ),
+        TaskName::TotalCountOfValidators => (
+            "the total number of validators from the consensus network",
+            total_count_of_validators,
+            &[],
+        ),
+        TaskName::TotalBalanceOfRelayers => (
+            "the total balance of relayers from the specific bridge",
+            total_balance_of_relayers,
+            &[Extra {
+                name: "relayer_balance",
+                help: "the balance of a relayer from the specific bridge",
+                kind: Kind::Gauge,
+                labels: &["relayer"],
+            }],
+        ),
+        TaskName::BridgedBalance => (
+            "the token balance of reserving safe on source chain",
+            bridged_balance,
+            &[],
+        ),
+        TaskName::BridgedSupply => (
+            "the token supply total minted on the destination chain",
+            bridged_supply,
+            &[],
+        ),
+        TaskName::NativeBalance => (
+            "the native balance of reserving safe on source chain",
+            native_balance,
+            &[],
+        ),
+        TaskName::GetPrice => (
+            "the close price of the related currency pair from gate.io",
+            get_price,
+            &[],
+        ),
+    };
+
+    Definition {
+        name: task_name.name(),
+        help,
+        kind: Kind::Gauge,
+        run,
+        extras,
+        upstream: match task_name {
+            TaskName::GetPrice => Some(get_price::UPSTREAM),
+            _ => None,
+        },
+    }
+}
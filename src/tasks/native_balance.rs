@@ -1,11 +1,14 @@
-use crate::config::ExtraOpts;
-use crate::utils::{diff_of_decimal_18, toi64_div_10pow12};
+use crate::utils::to_f64_with_decimal;
+use crate::{client::Client, config::ExtraOpts, tasks::Sample};
 
 use anyhow::{bail, Context, Result};
-use prometheus::core::Number;
 use serde_json::Value;
 
-pub(crate) fn native_balance<N: Number>(addr: &str, opts: &Option<ExtraOpts>) -> Result<N> {
+pub(crate) fn native_balance(
+    client: &Client,
+    addr: &str,
+    opts: &Option<ExtraOpts>,
+) -> Result<Vec<Sample>> {
     let (native_addr, decimal) = match opts {
         Some(ExtraOpts::NativeBalance {
             native_address,
@@ -19,28 +22,24 @@ pub(crate) fn native_balance<N: Number>(addr: &str, opts: &Option<ExtraOpts>) ->
         }
     };
 
-    let data: Value = ureq::post(addr)
-        .send_json(ureq::json!({
-            "method":"eth_getBalance",
-            "jsonrpc":"2.0",
-            "id":0,
-            "params":[
-                native_addr,
-                "latest"
-            ],
-        }))
+    let data: Value = client
+        .post_json(
+            addr,
+            &ureq::json!({
+                "method":"eth_getBalance",
+                "jsonrpc":"2.0",
+                "id":0,
+                "params":[
+                    native_addr,
+                    "latest"
+                ],
+            }),
+        )
         .with_context(|| {
             format!(
                 "requesting eth_getBalance ureq call failed, addr:{:?}, opts:{:?}",
                 addr, opts
             )
-        })?
-        .into_json()
-        .with_context(|| {
-            format!(
-                "requesting eth_getBalance ureq json failed, addr:{:?}, opts:{:?}",
-                addr, opts
-            )
         })?;
 
     let balance = &data["result"];
@@ -69,10 +68,7 @@ pub(crate) fn native_balance<N: Number>(addr: &str, opts: &Option<ExtraOpts>) ->
         ),
     };
 
-    Ok(N::from_i64(toi64_div_10pow12(
-        balance,
-        diff_of_decimal_18(decimal),
-    )))
+    Ok(vec![Sample::new(to_f64_with_decimal(balance, decimal))])
 }
 
 #[cfg(test)]
@@ -81,7 +77,8 @@ mod tests {
 
     #[test]
     fn test_native_balance() {
-        assert!(native_balance::<u64>(
+        assert!(native_balance(
+            &Client::default(),
             "https://data-seed-prebsc-1-s1.binance.org:8545",
             &Some(ExtraOpts::NativeBalance {
                 native_address: "0xae13d989dac2f0debff460ac112a837c89baa7cd".to_string(),
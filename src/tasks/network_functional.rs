@@ -1,16 +1,18 @@
-use crate::config::ExtraOpts;
+use crate::{client::Client, config::ExtraOpts, tasks::Sample};
 
 use anyhow::{bail, Context, Result};
-use prometheus::core::Number;
+use log::warn;
 use serde_json::Value;
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
-pub(crate) fn network_functional<N: Number>(addr: &str, _opts: &Option<ExtraOpts>) -> Result<N> {
-    let data: Value = ureq::get(&format!("{}/status", addr))
-        .call()
-        .with_context(|| format!("ureq call failed, addr:{:?}", addr))?
-        .into_json()
-        .with_context(|| format!("ureq json failed, addr:{:?}", addr))?;
+pub(crate) fn network_functional(
+    client: &Client,
+    addr: &str,
+    _opts: &Option<ExtraOpts>,
+) -> Result<Vec<Sample>> {
+    let data: Value = client
+        .get_json(&format!("{}/status", addr))
+        .with_context(|| format!("ureq call failed, addr:{:?}", addr))?;
 
     let latest_block_time = &data["result"]["sync_info"]["latest_block_time"];
     if latest_block_time.is_null() {
@@ -28,17 +30,58 @@ pub(crate) fn network_functional<N: Number>(addr: &str, _opts: &Option<ExtraOpts
         .unix_timestamp();
     let cur_timestamp = OffsetDateTime::now_utc().unix_timestamp();
 
-    Ok(N::from_i64((cur_timestamp - latest_block_timestamp).abs()))
+    // negative when the latest block time is ahead of the local clock
+    let mut samples = vec![Sample::new((cur_timestamp - latest_block_timestamp) as f64)];
+
+    // the height is best-effort, a node without it still tells how far behind it is
+    let latest_block_height = &data["result"]["sync_info"]["latest_block_height"];
+    match latest_block_height.as_str().map(str::parse::<u64>) {
+        Some(Ok(v)) => samples.push(Sample::extra("blocks_total", vec![], v as f64)),
+        _ => warn!(
+            "latest_block_height:{} is not a u64 str, addr:{:?}",
+            latest_block_height, addr
+        ),
+    }
+
+    Ok(samples)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::serve;
 
     #[test]
     fn test_task_network_functional() {
-        assert!(
-            network_functional::<u64>("https://prod-mainnet.prod.findora.org:26657", &None).is_ok()
+        assert!(network_functional(
+            &Client::default(),
+            "https://prod-mainnet.prod.findora.org:26657",
+            &None
         )
+        .is_ok())
+    }
+
+    #[test]
+    fn test_task_network_functional_ahead_of_local_clock() {
+        let addr = serve(|_| {
+            let body = r#"{"result":{"sync_info":{"latest_block_time":"2999-01-14T13:44:55.889015796Z","latest_block_height":"42"}}}"#;
+            tiny_http::Response::from_string(body).boxed()
+        });
+
+        let got = network_functional(&Client::default(), &addr, &None).unwrap();
+        assert!(got[0].value < 0.0);
+        assert_eq!(Sample::extra("blocks_total", vec![], 42.0), got[1]);
+    }
+
+    #[test]
+    fn test_task_network_functional_without_height() {
+        let addr = serve(|_| {
+            let body = r#"{"result":{"sync_info":{"latest_block_time":"2022-01-14T13:44:55.889015796Z"}}}"#;
+            tiny_http::Response::from_string(body).boxed()
+        });
+
+        let got = network_functional(&Client::default(), &addr, &None).unwrap();
+        assert_eq!(1, got.len());
+        assert!(got[0].value > 0.0);
     }
 }
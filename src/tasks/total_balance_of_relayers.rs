@@ -1,14 +1,14 @@
-use crate::config::ExtraOpts;
-use crate::utils::{diff_of_decimal_18, toi64_div_10pow12};
+use crate::utils::to_f64_with_decimal;
+use crate::{client::Client, config::ExtraOpts, tasks::Sample};
 
 use anyhow::{bail, Context, Result};
-use prometheus::core::Number;
 use serde_json::Value;
 
-pub(crate) fn total_balance_of_relayers<N: Number>(
+pub(crate) fn total_balance_of_relayers(
+    client: &Client,
     addr: &str,
     opts: &Option<ExtraOpts>,
-) -> Result<N> {
+) -> Result<Vec<Sample>> {
     let (bridge_addr, decimal) = match opts {
         Some(ExtraOpts::TotalBalanceOfRelayers {
             bridge_address,
@@ -18,35 +18,31 @@ pub(crate) fn total_balance_of_relayers<N: Number>(
     };
 
     // asking the bridge for the count of relayers
-    let data: Value = ureq::post(addr)
-        .send_json(ureq::json!({
-            "method":"eth_call",
-            "jsonrpc":"2.0",
-            "id":0,
-            "params":[
-                {
-                    // keccak256("_totalRelayers()")[:8] = "0x802aabe8"
-                    // https://github.com/ChainSafe/chainbridge-solidity/blob/master/contracts/Bridge.sol#L314
-                    //
-                    // no params so only function signature
-                    "data":"0x802aabe8",
-                    "to":bridge_addr
-                },
-                "latest"
-            ],
-        }))
+    let data: Value = client
+        .post_json(
+            addr,
+            &ureq::json!({
+                "method":"eth_call",
+                "jsonrpc":"2.0",
+                "id":0,
+                "params":[
+                    {
+                        // keccak256("_totalRelayers()")[:8] = "0x802aabe8"
+                        // https://github.com/ChainSafe/chainbridge-solidity/blob/master/contracts/Bridge.sol#L314
+                        //
+                        // no params so only function signature
+                        "data":"0x802aabe8",
+                        "to":bridge_addr
+                    },
+                    "latest"
+                ],
+            }),
+        )
         .with_context(|| {
             format!(
                 "ask relayer count ureq call failed, addr:{:?}, opts:{:?}",
                 addr, opts
             )
-        })?
-        .into_json()
-        .with_context(|| {
-            format!(
-                "ask relayer count ureq json failed, addr:{:?}, opts:{:?}",
-                addr, opts
-            )
         })?;
 
     let count = &data["result"];
@@ -98,20 +94,13 @@ pub(crate) fn total_balance_of_relayers<N: Number>(
         }))
     }
 
-    let data: Value = ureq::post(addr)
-        .send_json(serde_json::Value::Array(reqs))
+    let data: Value = client
+        .post_json(addr, &serde_json::Value::Array(reqs))
         .with_context(|| {
             format!(
                 "ask relayer addresses ureq call failed, addr:{:?}, opts:{:?}",
                 addr, opts
             )
-        })?
-        .into_json()
-        .with_context(|| {
-            format!(
-                "ask relayer addresses ureq json failed, addr:{:?}, opts:{:?}",
-                addr, opts
-            )
         })?;
 
     let data = data.as_array().with_context(|| {
@@ -171,20 +160,13 @@ pub(crate) fn total_balance_of_relayers<N: Number>(
         }));
     }
 
-    let data: Value = ureq::post(addr)
-        .send_json(serde_json::Value::Array(reqs))
+    let data: Value = client
+        .post_json(addr, &serde_json::Value::Array(reqs))
         .with_context(|| {
             format!(
                 "ask relayer balances ureq call failed, addr:{:?}, opts:{:?}",
                 addr, opts
             )
-        })?
-        .into_json()
-        .with_context(|| {
-            format!(
-                "ask relayer balances ureq json failed, addr:{:?}, opts:{:?}",
-                addr, opts
-            )
         })?;
 
     let data = data.as_array().with_context(|| {
@@ -194,7 +176,7 @@ pub(crate) fn total_balance_of_relayers<N: Number>(
         )
     })?;
 
-    let mut balances: i64 = 0;
+    let mut samples = vec![];
     for d in data {
         let balance = &d["result"];
         if balance.is_null() {
@@ -206,6 +188,17 @@ pub(crate) fn total_balance_of_relayers<N: Number>(
             )
         }
 
+        // the responses of a batch request can be in any order
+        let relayer = match d["id"].as_u64().and_then(|i| relayers.get(i as usize)) {
+            Some(relayer) => relayer,
+            None => bail!(
+                "the balance id is unknown: {}, addr:{:?}, opts:{:?}",
+                d,
+                addr,
+                opts
+            ),
+        };
+
         let balance = match balance.as_str() {
             Some(v) => u128::from_str_radix(v.trim_start_matches("0x"), 16).with_context(|| {
                 format!(
@@ -221,20 +214,30 @@ pub(crate) fn total_balance_of_relayers<N: Number>(
             ),
         };
 
-        balances += toi64_div_10pow12(balance, diff_of_decimal_18(decimal));
+        samples.push(Sample::extra(
+            "relayer_balance",
+            vec![relayer.clone()],
+            to_f64_with_decimal(balance, decimal),
+        ));
     }
 
-    // the real balances needs to div by 8 again
-    Ok(N::from_i64(balances))
+    // the same relayers in the same order sum up to the same total,
+    // endpoints of a quorum compare the samples as a whole
+    samples.sort_by(|a, b| a.labels.cmp(&b.labels));
+    let balances = samples.iter().map(|s| s.value).sum();
+    samples.insert(0, Sample::new(balances));
+    Ok(samples)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::serve;
 
     #[test]
     fn test_task_total_balance_of_relayers() {
-        assert!(total_balance_of_relayers::<u64>(
+        assert!(total_balance_of_relayers(
+            &Client::default(),
             "https://data-seed-prebsc-1-s1.binance.org:8545",
             &Some(ExtraOpts::TotalBalanceOfRelayers {
                 bridge_address: "0xD609931ec1c7a7F6ad59A69fede03fB067Af997c".to_string(),
@@ -243,4 +246,47 @@ mod tests {
         )
         .is_ok());
     }
+
+    #[test]
+    fn test_task_total_balance_of_relayers_per_relayer() {
+        let addr = serve(|request| {
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            let body: Value = serde_json::from_str(&body).unwrap();
+            let resp = match body[0]["method"].as_str() {
+                // the relayer count
+                None => ureq::json!({"id":0, "result":"0x2"}),
+                Some("eth_call") => ureq::json!([
+                    {"id":0, "result":format!("0x{:064x}", 0xa)},
+                    {"id":1, "result":format!("0x{:064x}", 0xb)},
+                ]),
+                // the balances in a different order than the requests
+                _ => ureq::json!([
+                    {"id":1, "result":"0x1bc16d674ec80000"},
+                    {"id":0, "result":"0xde0b6b3a7640000"},
+                ]),
+            };
+            tiny_http::Response::from_string(resp.to_string()).boxed()
+        });
+
+        let got = total_balance_of_relayers(
+            &Client::default(),
+            &addr,
+            &Some(ExtraOpts::TotalBalanceOfRelayers {
+                bridge_address: "0xD609931ec1c7a7F6ad59A69fede03fB067Af997c".to_string(),
+                decimal: 18,
+            }),
+        )
+        .unwrap();
+
+        let relayer = |n: u8| format!("0x{:040x}", n);
+        assert_eq!(
+            vec![
+                Sample::new(3.0),
+                Sample::extra("relayer_balance", vec![relayer(0xa)], 1.0),
+                Sample::extra("relayer_balance", vec![relayer(0xb)], 2.0),
+            ],
+            got
+        );
+    }
 }
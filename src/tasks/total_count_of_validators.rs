@@ -1,19 +1,17 @@
-use crate::config::ExtraOpts;
+use crate::{client::Client, config::ExtraOpts, tasks::Sample};
 
 use anyhow::{bail, Context, Result};
 
-use prometheus::core::Number;
 use serde_json::Value;
 
-pub(crate) fn total_count_of_validators<N: Number>(
+pub(crate) fn total_count_of_validators(
+    client: &Client,
     addr: &str,
     _opts: &Option<ExtraOpts>,
-) -> Result<N> {
-    let data: Value = ureq::get(&format!("{}/validators", addr))
-        .call()
-        .with_context(|| format!("ureq call failed, addr:{:?}", addr))?
-        .into_json()
-        .with_context(|| format!("ureq json failed, addr:{:?}", addr))?;
+) -> Result<Vec<Sample>> {
+    let data: Value = client
+        .get_json(&format!("{}/validators", addr))
+        .with_context(|| format!("ureq call failed, addr:{:?}", addr))?;
 
     let total_validators = &data["result"]["total"];
     if total_validators.is_null() {
@@ -32,7 +30,7 @@ pub(crate) fn total_count_of_validators<N: Number>(
         )
     })?;
 
-    Ok(N::from_i64(total_validators))
+    Ok(vec![Sample::new(total_validators as f64)])
 }
 
 #[cfg(test)]
@@ -41,7 +39,8 @@ mod tests {
 
     #[test]
     fn test_task_total_count_of_validators() {
-        assert!(total_count_of_validators::<u64>(
+        assert!(total_count_of_validators(
+            &Client::default(),
             "https://prod-mainnet.prod.findora.org:26657",
             &None
         )
@@ -1,6 +1,6 @@
 use std::{
-    collections::hash_map::DefaultHasher,
-    hash::{Hash, Hasher},
+    collections::hash_map::{DefaultHasher, RandomState},
+    hash::{BuildHasher, Hash, Hasher},
 };
 
 pub fn calculate_hash<T: Hash>(t: &T) -> u64 {
@@ -9,32 +9,89 @@ pub fn calculate_hash<T: Hash>(t: &T) -> u64 {
     s.finish()
 }
 
-/// Returns a difference of 18 - decimal.
-/// If the decimal is bigger or equal to 18 will return 0.
-pub fn diff_of_decimal_18(decimal: &usize) -> u32 {
-    if *decimal >= 18 {
+/// Returns the scheme and authority part of an URL,
+/// e.g. https://prod-mainnet.prod.findora.org:26657/status returns https://prod-mainnet.prod.findora.org:26657
+pub fn host_of(addr: &str) -> &str {
+    let start = addr.find("://").map(|i| i + 3).unwrap_or(0);
+    match addr[start..].find('/') {
+        Some(end) => &addr[..start + end],
+        None => addr,
+    }
+}
+
+/// Returns true if the name follows the Prometheus naming rules of metrics,
+/// the colons reserved for recording rules are not allowed.
+pub fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Returns a pseudo random number between 0 and max inclusive.
+///
+/// Every RandomState is seeded with different keys,
+/// which is random enough for jittering without pulling in a rand crate.
+pub fn jitter(max: u64) -> u64 {
+    if max == 0 {
         return 0;
     }
-    (18 - *decimal) as u32
+    RandomState::new().build_hasher().finish() % (max + 1)
+}
+
+/// Returns the balance in real units of a token with the decimal,
+/// e.g. 9989580120000000000 of a token with 18 decimals returns 9.98958012
+pub fn to_f64_with_decimal(balance: u128, decimal: &usize) -> f64 {
+    balance as f64 / 10f64.powi(*decimal as i32)
 }
 
-/// The balance number is like below:
-/// 9989580120000000000
-/// and it is using the 18th number as it's decimal point
-/// 9989580120000000000 = 9.989580120000000000
-/// and the max i64 is 9989580120000000000 a 19th number
-/// so for filling this huge number into i64 we div by 12.
-/// the real balances needs to div by 6 again
-pub fn toi64_div_10pow12(balance: u128, diff_decimal: u32) -> i64 {
-    balance
-        .wrapping_mul(10u128.pow(diff_decimal))
-        .wrapping_div(10u128.pow(12)) as i64
+/// Returns the decoded value of an URL query component,
+/// '+' is decoded as a space and None is returned on an invalid escape or UTF-8.
+pub fn percent_decode(v: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(v.len());
+    let mut iter = v.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(
+            Some("host:https://prod.findora.org:8545".to_string()),
+            percent_decode("host%3Ahttps%3A%2F%2Fprod.findora.org%3A8545")
+        );
+        assert_eq!(Some("a b c".to_string()), percent_decode("a+b%20c"));
+        assert_eq!(None, percent_decode("%zz"));
+        assert_eq!(None, percent_decode("%4"));
+        assert_eq!(None, percent_decode("%ff"));
+    }
+
+    #[test]
+    fn test_is_valid_metric_name() {
+        assert!(is_valid_metric_name("findora_bridge_reserve_usdt"));
+        assert!(is_valid_metric_name("_bridge2"));
+        assert!(!is_valid_metric_name(""));
+        assert!(!is_valid_metric_name("2bridge"));
+        assert!(!is_valid_metric_name("bridge-reserve"));
+        assert!(!is_valid_metric_name("bridge:reserve"));
+    }
+
     #[test]
     fn test_calculate_hash() {
         #[derive(Hash)]
@@ -59,17 +116,27 @@ mod tests {
     }
 
     #[test]
-    fn test_diff_of_decimal_18() {
-        assert_eq!(15, diff_of_decimal_18(&3));
-        assert_eq!(8, diff_of_decimal_18(&10));
-        assert_eq!(7, diff_of_decimal_18(&11));
-        assert_eq!(0, diff_of_decimal_18(&18));
-        assert_eq!(0, diff_of_decimal_18(&20));
+    fn test_host_of() {
+        assert_eq!(
+            "https://prod-mainnet.prod.findora.org:26657",
+            host_of("https://prod-mainnet.prod.findora.org:26657/status")
+        );
+        assert_eq!("http://127.0.0.1:8545", host_of("http://127.0.0.1:8545"));
+        assert_eq!("127.0.0.1:8545", host_of("127.0.0.1:8545/"));
+    }
+
+    #[test]
+    fn test_jitter() {
+        assert_eq!(0, jitter(0));
+        for _ in 0..100 {
+            assert!(jitter(10) <= 10);
+        }
     }
 
     #[test]
-    fn test_toi64_div_10pow12() {
-        assert_eq!(9989580, toi64_div_10pow12(9989580120000000000, 0));
-        assert_eq!(538800000, toi64_div_10pow12(538800000, 12));
+    fn test_to_f64_with_decimal() {
+        assert_eq!(9.98958012, to_f64_with_decimal(9989580120000000000, &18));
+        assert_eq!(538.8, to_f64_with_decimal(538800000, &6));
+        assert_eq!(538800000.0, to_f64_with_decimal(538800000, &0));
     }
 }